@@ -0,0 +1,185 @@
+use alsa::seq::{
+    Addr, EvCtrl, EvNote, EvQueueControl, Event, EventType, PortCap, PortSubscribe, PortType, Seq,
+};
+use alsa::Direction;
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::time::Duration;
+use utils::programclock::now;
+
+use crate::backend::MidiOut;
+
+const OUTPUT_POOL: u32 = 2000; // Events the kernel can hold for us, must cover the lookahead
+
+/* Output through the ALSA sequencer. Messages are queued with real time stamps
+ * on a sequencer queue and the kernel delivers them on time, independent of how
+ * precisely the clock thread wakes up. The queue is started when connecting,
+ * its time is program time minus origin. */
+pub struct AlsaSeqOut {
+    seq: Seq,
+    port: i32,
+    queue: i32,
+    origin: Duration,
+}
+
+impl AlsaSeqOut {
+    pub fn connect(port_name: &str, client_name: &str) -> Result<AlsaSeqOut> {
+        let dest = port_addr(port_name)?;
+        AlsaSeqOut::open(client_name, client_name, Some(dest))
+    }
+
+    /// A port named port_name other applications subscribe to themselves
+    pub fn create_virtual(port_name: &str, client_name: &str) -> Result<AlsaSeqOut> {
+        AlsaSeqOut::open(port_name, client_name, None)
+    }
+
+    fn open(port_name: &str, client_name: &str, dest: Option<Addr>) -> Result<AlsaSeqOut> {
+        let seq = Seq::open(None, Some(Direction::Playback), false)
+            .context("Failed to open ALSA sequencer")?;
+        let name = CString::new(client_name)?;
+        seq.set_client_name(&name)?;
+        seq.set_client_pool_output(OUTPUT_POOL)?;
+        let port = seq
+            .create_simple_port(
+                &CString::new(port_name)?,
+                PortCap::READ | PortCap::SUBS_READ,
+                PortType::MIDI_GENERIC | PortType::APPLICATION,
+            )
+            .context("Failed to create sequencer port")?;
+        if let Some(dest) = dest {
+            let subscription = PortSubscribe::empty()?;
+            subscription.set_sender(Addr {
+                client: seq.client_id()?,
+                port,
+            });
+            subscription.set_dest(dest);
+            seq.subscribe_port(&subscription)
+                .with_context(|| format!("Failed to connect to {}:{}", dest.client, dest.port))?;
+        }
+
+        let queue = seq.alloc_named_queue(&name)?;
+        seq.control_queue(queue, EventType::Start, 0, None)?;
+        seq.drain_output()?;
+        Ok(AlsaSeqOut {
+            seq,
+            port,
+            queue,
+            origin: now().0,
+        })
+    }
+
+    fn output(&mut self, time: Option<Duration>, msg: &[u8]) -> Result<()> {
+        let mut ev = encode(msg)?;
+        ev.set_source(self.port);
+        ev.set_subs();
+        match time {
+            Some(time) => ev.schedule_real(self.queue, false, time.saturating_sub(self.origin)),
+            None => ev.set_direct(),
+        }
+        self.seq
+            .event_output(&mut ev)
+            .context("Failed to queue sequencer event")?;
+        self.seq.drain_output()?;
+        Ok(())
+    }
+}
+
+impl MidiOut for AlsaSeqOut {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.output(None, msg)
+    }
+
+    fn timestamped(&self) -> bool {
+        true
+    }
+
+    fn send_at(&mut self, time: Duration, msg: &[u8]) -> Result<()> {
+        self.output(Some(time), msg)
+    }
+
+    fn cancel(&mut self) -> Result<()> {
+        // Removes the events of this client from the queue as well
+        self.seq
+            .drop_output()
+            .context("Failed to drop queued sequencer events")
+    }
+}
+
+impl Drop for AlsaSeqOut {
+    fn drop(&mut self) {
+        let _ = self.seq.control_queue(self.queue, EventType::Stop, 0, None);
+        let _ = self.seq.drain_output();
+    }
+}
+
+// Port names from midir end with the sequencer address, e.g. "Client:Port 20:0"
+fn port_addr(port_name: &str) -> Result<Addr> {
+    let addr = port_name.rsplit(' ').next().unwrap_or_default();
+    match addr.parse() {
+        Ok(addr) => Ok(addr),
+        Err(_) => bail!("No sequencer address in port name {:?}", port_name),
+    }
+}
+
+fn encode(msg: &[u8]) -> Result<Event<'_>> {
+    let data = |idx: usize| msg.get(idx).copied().unwrap_or(0);
+    let ctrl = |param: u32, value: i32| EvCtrl {
+        channel: data(0) & 0x0F,
+        param,
+        value,
+    };
+    let realtime = |t: EventType| {
+        Event::new(
+            t,
+            &EvQueueControl {
+                queue: 0,
+                value: (),
+            },
+        )
+    };
+    let ev = match data(0) {
+        0xF8 => realtime(EventType::Clock),
+        0xFA => realtime(EventType::Start),
+        0xFB => realtime(EventType::Continue),
+        0xFC => realtime(EventType::Stop),
+        0xFE => Event::new(EventType::Sensing, &()),
+        0xFF => Event::new(EventType::Reset, &()),
+        0xF6 => Event::new(EventType::TuneRequest, &()),
+        0xF1 => Event::new(EventType::Qframe, &ctrl(0, data(1) as i32)),
+        0xF2 => {
+            let position = data(1) as i32 | (data(2) as i32) << 7;
+            Event::new(EventType::Songpos, &ctrl(0, position))
+        }
+        0xF3 => Event::new(EventType::Songsel, &ctrl(0, data(1) as i32)),
+        0xF0 => Event::new_ext(EventType::Sysex, msg),
+        status => {
+            let note = EvNote {
+                channel: status & 0x0F,
+                note: data(1),
+                velocity: data(2),
+                off_velocity: 0,
+                duration: 0,
+            };
+            match status & 0xF0 {
+                0x80 => Event::new(EventType::Noteoff, &note),
+                0x90 => Event::new(EventType::Noteon, &note),
+                0xB0 => Event::new(EventType::Controller, &ctrl(data(1) as u32, data(2) as i32)),
+                0xC0 => Event::new(EventType::Pgmchange, &ctrl(0, data(1) as i32)),
+                _ => bail!("Unsupported message for the sequencer: {:02X?}", msg),
+            }
+        }
+    };
+    Ok(ev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_port_addr() {
+        let addr = port_addr("Midi Through:Midi Through Port-0 14:0").unwrap();
+        assert_eq!((addr.client, addr.port), (14, 0));
+        assert!(port_addr("No address").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::time::Duration;
+
+use crate::multisync::{PortInfo, PortKind};
+
+/// How MIDI messages get to the ports
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Backend {
+    /// Send every message the moment it is due (midir)
+    Midir,
+    /// Hand messages to an ALSA sequencer queue ahead of time, the kernel delivers them (Linux)
+    AlsaSeq,
+    /// Write messages sample accurately to JACK MIDI ports
+    Jack,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutputOptions {
+    pub backend: Backend,
+    /// How far ahead messages are handed to timestamped backends
+    pub lookahead: Duration,
+}
+
+/// Connection to a single output port
+pub trait MidiOut: Send {
+    /// Send a message right away
+    fn send(&mut self, msg: &[u8]) -> Result<()>;
+
+    /// True if the port delivers messages handed over with send_at at their time
+    fn timestamped(&self) -> bool {
+        false
+    }
+
+    /// Deliver a message at time (program time), sent right away if the port is not timestamped
+    fn send_at(&mut self, _time: Duration, msg: &[u8]) -> Result<()> {
+        self.send(msg)
+    }
+
+    /// Drop messages handed over with send_at that were not delivered yet
+    fn cancel(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl MidiOut for MidiOutputConnection {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        MidiOutputConnection::send(self, msg)?;
+        Ok(())
+    }
+}
+
+pub fn connect(backend: Backend, port: &PortInfo, name: &str) -> Result<Box<dyn MidiOut>> {
+    // Network peers are reached the same way with every backend
+    if let PortKind::Network(peer) = &port.port {
+        return Ok(Box::new(crate::rtpmidi::RtpOut::new(peer.clone())));
+    }
+    match backend {
+        Backend::Midir => {
+            let output = MidiOutput::new(name)?;
+            let conn = match &port.port {
+                PortKind::Device(device) => output.connect(device, name),
+                #[cfg(unix)]
+                PortKind::Virtual => {
+                    midir::os::unix::VirtualOutput::create_virtual(output, &port.name)
+                }
+                #[cfg(not(unix))]
+                PortKind::Virtual => anyhow::bail!("Virtual ports are only available on Unix"),
+                PortKind::Network(_) => unreachable!("Network peers are connected above"),
+            }
+            .map_err(|e| anyhow::anyhow!("{:?}: {}", port, e))?;
+            Ok(Box::new(conn))
+        }
+        #[cfg(target_os = "linux")]
+        Backend::AlsaSeq => {
+            let out = match port.port {
+                PortKind::Device(_) => crate::alsaseq::AlsaSeqOut::connect(&port.name, name),
+                PortKind::Virtual => crate::alsaseq::AlsaSeqOut::create_virtual(&port.name, name),
+                PortKind::Network(_) => unreachable!("Network peers are connected above"),
+            }
+            .with_context(|| format!("{:?}", port))?;
+            Ok(Box::new(out))
+        }
+        #[cfg(feature = "jack")]
+        Backend::Jack => {
+            let out = match port.port {
+                PortKind::Device(_) => crate::jackmidi::JackOut::connect(&port.name, name),
+                PortKind::Virtual => crate::jackmidi::JackOut::create_virtual(&port.name, name),
+                PortKind::Network(_) => unreachable!("Network peers are connected above"),
+            }
+            .with_context(|| format!("{:?}", port))?;
+            Ok(Box::new(out))
+        }
+        #[cfg(not(feature = "jack"))]
+        Backend::Jack => anyhow::bail!("midimaxe was built without JACK support"),
+        #[cfg(not(target_os = "linux"))]
+        Backend::AlsaSeq => anyhow::bail!("The ALSA sequencer backend is only available on Linux"),
+    }
+}
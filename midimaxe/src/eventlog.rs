@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::fmt::Debug;
+use std::path::Path;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use utils::programclock::{now, ProgramTime};
+
+const UI_CHANNEL_SIZE: usize = 1024;
+const MAX_LOG_FILES: usize = 14; // Daily log files to keep
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub time: ProgramTime,
+    pub level: Level,
+    pub message: String,
+}
+
+/* Forwards log events to the UI. Events are dropped when the UI does not keep
+ * up, logging must never block the timing thread. */
+struct UiLayer {
+    tx: Sender<LogLine>,
+}
+
+#[derive(Default)]
+struct LineVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+/// Set up logging to the UI and optionally to daily rotated JSON lines files in log_dir.
+/// The returned guard flushes the log file when dropped.
+pub fn init(log_dir: Option<&Path>) -> Result<(Receiver<LogLine>, Option<WorkerGuard>)> {
+    let (tx, rx) = bounded(UI_CHANNEL_SIZE);
+    let (file, guard) = match log_dir {
+        Some(dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("midimaxe")
+                .filename_suffix("jsonl")
+                .max_log_files(MAX_LOG_FILES)
+                .build(dir)
+                .with_context(|| format!("Failed to create log file in {:?}", dir))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_thread_ids(true)
+                .with_writer(writer)
+                .with_filter(LevelFilter::INFO);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(UiLayer { tx }.with_filter(LevelFilter::INFO))
+        .with(file)
+        .try_init()
+        .context("Failed to set up logging")?;
+    Ok((rx, guard))
+}
+
+impl<S: Subscriber> Layer<S> for UiLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut visitor = LineVisitor::default();
+        event.record(&mut visitor);
+        let _ = self.tx.try_send(LogLine {
+            time: now(),
+            level: *event.metadata().level(),
+            message: visitor.finish(),
+        });
+    }
+}
+
+impl Visit for LineVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_owned();
+        } else {
+            self.fields.push(format!("{}={}", field.name(), value));
+        }
+    }
+}
+
+impl LineVisitor {
+    fn finish(self) -> String {
+        if self.fields.is_empty() {
+            self.message
+        } else {
+            format!("{} {}", self.message, self.fields.join(" "))
+        }
+    }
+}
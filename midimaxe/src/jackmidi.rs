@@ -0,0 +1,351 @@
+use anyhow::{bail, Context, Result};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use jack::jack_sys as j;
+use jack::{AsyncClient, Client, ClientOptions, Control, Port, PortFlags, ProcessScope, RawMidi};
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::{info, warn};
+use utils::programclock::{now, ProgramTime};
+
+use crate::backend::MidiOut;
+use crate::multisync::{
+    BeatGrid, MultiSyncCommand, MultiSyncEvent, MultiSyncState, Settings, TimeSignature,
+};
+
+const QUEUE_SIZE: usize = 1024; // Messages waiting for their cycle, must cover the lookahead
+const TICKS_PER_BEAT: f64 = 1920.0; // Resolution of the BBT position
+const MAX_MESSAGE: usize = 16; // Longest message, fits an MTC full frame without allocating
+
+#[derive(Debug, Clone, Copy)]
+struct JackEvent {
+    usecs: u64,
+    generation: u64, // Cancels before the message was sent
+    len: usize,
+    bytes: [u8; MAX_MESSAGE],
+}
+
+/* Output through a JACK MIDI port. Messages are handed to the process callback
+ * ahead of time and written at the frame offset of their time stamp within the
+ * cycle, so ticks are sample accurate relative to the JACK clock. Messages leave
+ * JACK a period after the cycle they are written in, the same for all ports. */
+pub struct JackOut {
+    _client: AsyncClient<(), Process>,
+    tx: Sender<JackEvent>,
+    generation: Arc<AtomicU64>, // Counts cancels, never blocks the clock thread
+    offset: i64,                // JACK time minus program time in µs
+}
+
+struct Process {
+    port: Port<jack::MidiOut>,
+    rx: Receiver<JackEvent>,
+    generation: Arc<AtomicU64>,
+    pending: Vec<JackEvent>, // Sorted by time, allocated once
+}
+
+impl JackOut {
+    /// Register an output port and connect it to the JACK port of the device if there is one
+    pub fn connect(port_name: &str, client_name: &str) -> Result<JackOut> {
+        let device = port_name.split(':').next().unwrap_or(port_name);
+        JackOut::open(device, client_name, true)
+    }
+
+    /// Register an output port that is left for other applications to connect
+    pub fn create_virtual(port_name: &str, client_name: &str) -> Result<JackOut> {
+        JackOut::open(port_name, client_name, false)
+    }
+
+    fn open(device: &str, client_name: &str, auto_connect: bool) -> Result<JackOut> {
+        let name = format!("{} {}", client_name, device);
+        let (client, _) = Client::new(&name, ClientOptions::NO_START_SERVER)
+            .context("Failed to connect to JACK")?;
+        let port = client
+            .register_port("clock", jack::MidiOut::default())
+            .context("Failed to register JACK MIDI port")?;
+        let own_port = port.name()?;
+        let offset = client.time() as i64 - now().0.as_micros() as i64;
+        let (tx, rx) = bounded(QUEUE_SIZE);
+        let generation = Arc::new(AtomicU64::new(0));
+        let process = Process {
+            port,
+            rx,
+            generation: generation.clone(),
+            pending: Vec::with_capacity(QUEUE_SIZE),
+        };
+        let client = client
+            .activate_async((), process)
+            .context("Failed to activate JACK client")?;
+
+        if !auto_connect {
+            info!(port = own_port, "JACK port created");
+            return Ok(JackOut {
+                _client: client,
+                tx,
+                generation,
+                offset,
+            });
+        }
+        let targets = client
+            .as_client()
+            .ports(None, Some(j::RAW_MIDI_TYPE), PortFlags::IS_INPUT);
+        let target = targets.into_iter().find(|target| {
+            let aliases = client
+                .as_client()
+                .port_by_name(target)
+                .and_then(|p| p.aliases().ok())
+                .unwrap_or_default();
+            target.contains(device) || aliases.iter().any(|a| a.contains(device))
+        });
+        match target {
+            Some(target) => {
+                client
+                    .as_client()
+                    .connect_ports_by_name(&own_port, &target)
+                    .with_context(|| format!("Failed to connect {} to {}", own_port, target))?;
+                info!(port = own_port, target, "JACK port connected");
+            }
+            None => info!(
+                port = own_port,
+                "No JACK port found for device, connect it manually"
+            ),
+        }
+        Ok(JackOut {
+            _client: client,
+            tx,
+            generation,
+            offset,
+        })
+    }
+}
+
+impl MidiOut for JackOut {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.send_at(now().0, msg)
+    }
+
+    fn timestamped(&self) -> bool {
+        true
+    }
+
+    fn send_at(&mut self, time: std::time::Duration, msg: &[u8]) -> Result<()> {
+        if msg.len() > MAX_MESSAGE {
+            bail!("Message too long for JACK output: {:02X?}", msg);
+        }
+        let mut bytes = [0; MAX_MESSAGE];
+        bytes[..msg.len()].copy_from_slice(msg);
+        let usecs = (time.as_micros() as i64 + self.offset).max(0) as u64;
+        self.tx
+            .try_send(JackEvent {
+                usecs,
+                generation: self.generation.load(Ordering::Relaxed),
+                len: msg.len(),
+                bytes,
+            })
+            .context("JACK output queue full")
+    }
+
+    // Messages sent before are dropped by the process callback
+    fn cancel(&mut self) -> Result<()> {
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl jack::ProcessHandler for Process {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let generation = self.generation.load(Ordering::Acquire);
+        self.pending.retain(|e| e.generation >= generation);
+        while let Ok(event) = self.rx.try_recv() {
+            // Never allocate here, drop the message if the queue is full
+            if event.generation >= generation && self.pending.len() < self.pending.capacity() {
+                let idx = self.pending.partition_point(|e| e.usecs <= event.usecs);
+                self.pending.insert(idx, event);
+            }
+        }
+        let mut writer = self.port.writer(ps);
+        let Ok(times) = ps.cycle_times() else {
+            return Control::Continue;
+        };
+        let frames = ps.n_frames();
+        let due = self.pending.partition_point(|e| e.usecs < times.next_usecs);
+        for JackEvent {
+            usecs, len, bytes, ..
+        } in self.pending.drain(..due)
+        {
+            // Late messages go out at the start of the cycle
+            let offset = usecs.saturating_sub(times.current_usecs) as f64;
+            let frame = (offset / times.period_usecs as f64 * frames as f64) as u32;
+            let _ = writer.write(&RawMidi {
+                time: frame.min(frames - 1),
+                bytes: &bytes[..len],
+            });
+        }
+        Control::Continue
+    }
+}
+
+/// What the timebase callback needs to know about the master clock
+#[derive(Clone)]
+struct Timebase {
+    settings: Settings,
+    grid: BeatGrid,
+    running: bool,
+    offset: i64, // JACK time minus program time in µs
+}
+
+struct TimebaseArg {
+    shared: Arc<Mutex<Timebase>>,
+    last: Timebase,
+}
+
+// Owned by the transport thread, which frees it once the callback is released
+struct TimebaseArgPtr(*mut TimebaseArg);
+
+// SAFETY: Only the timebase callback dereferences the pointer while it is registered
+unsafe impl Send for TimebaseArgPtr {}
+
+/* Act as JACK transport timebase master, so other JACK clients follow the tempo,
+ * time signature and position of the master beat grid. The transport is started
+ * and stopped together with the clock. The thread ends with the control thread. */
+pub fn spawn_timebase(cmd: &Sender<MultiSyncCommand>) -> Result<JoinHandle<()>> {
+    let (client, _) = Client::new("midimaxe transport", ClientOptions::NO_START_SERVER)
+        .context("Failed to connect to JACK")?;
+    let timebase = Timebase {
+        settings: Settings::new(120.0, 4.0, None),
+        grid: BeatGrid::new(now(), 0.0),
+        running: false,
+        offset: client.time() as i64 - now().0.as_micros() as i64,
+    };
+    let shared = Arc::new(Mutex::new(timebase.clone()));
+    let arg = TimebaseArgPtr(Box::into_raw(Box::new(TimebaseArg {
+        shared: shared.clone(),
+        last: timebase,
+    })));
+    // SAFETY: The argument is only used by the callback and freed after the client is closed
+    let err = unsafe {
+        j::jack_set_timebase_callback(client.raw(), 0, Some(timebase_callback), arg.0.cast())
+    };
+    if err != 0 {
+        // SAFETY: Not registered, so the callback will never see it
+        drop(unsafe { Box::from_raw(arg.0) });
+        bail!("Failed to become JACK timebase master ({})", err);
+    }
+    let client = client
+        .activate_async((), ())
+        .context("Failed to activate JACK client")?;
+
+    let (s, events) = unbounded();
+    cmd.send(MultiSyncCommand::AddListener(s))
+        .context("Failed to subscribe to display updates")?;
+    info!("JACK timebase master");
+    thread::Builder::new()
+        .name("midimaxe jack transport".to_owned())
+        .spawn(move || {
+            let arg = arg;
+            for event in events.iter() {
+                let MultiSyncEvent::DisplayUpdate(disp) = event else {
+                    continue;
+                };
+                let running = matches!(disp.state, MultiSyncState::Started(_));
+                let Ok(mut timebase) = shared.lock() else {
+                    break;
+                };
+                if running != timebase.running {
+                    let transport = client.as_client().transport();
+                    let result = match running {
+                        true => transport.start(),
+                        false => transport.stop().and_then(|_| transport.locate(0)),
+                    };
+                    if let Err(e) = result {
+                        warn!(error = ?e, "Failed to control JACK transport");
+                    }
+                }
+                timebase.settings = disp.settings;
+                timebase.grid = disp.grid;
+                timebase.running = running;
+            }
+            // SAFETY: Release the callback before its argument is freed
+            unsafe { j::jack_release_timebase(client.as_client().raw()) };
+            drop(client);
+            drop(unsafe { Box::from_raw(arg.0) });
+        })
+        .context("Failed to start JACK transport thread")
+}
+
+unsafe extern "C" fn timebase_callback(
+    _state: j::jack_transport_state_t,
+    _nframes: j::jack_nframes_t,
+    pos: *mut j::jack_position_t,
+    _new_pos: c_int,
+    arg: *mut c_void,
+) {
+    // SAFETY: arg is the TimebaseArg registered with the callback, pos is valid for the call
+    let (arg, pos) = unsafe { (&mut *(arg as *mut TimebaseArg), &mut *pos) };
+    // Never wait for the lock in the process thread, use the last state instead
+    if let Ok(timebase) = arg.shared.try_lock() {
+        arg.last = timebase.clone();
+    }
+    let timebase = &arg.last;
+    let quarter = match timebase.running {
+        true => {
+            let time = ProgramTime(std::time::Duration::from_micros(
+                (pos.usecs as i64 - timebase.offset).max(0) as u64,
+            ));
+            timebase.settings.get_quarter(timebase.grid, Some(time))
+        }
+        false => 0.0,
+    };
+    let bbt = Bbt::at(quarter, timebase.settings.time_signature);
+    let signature = timebase.settings.time_signature;
+    pos.valid = j::JackPositionBBT;
+    pos.bar = bbt.bar;
+    pos.beat = bbt.beat;
+    pos.tick = bbt.tick;
+    pos.bar_start_tick = bbt.bar_start_tick;
+    pos.beats_per_bar = signature.num as f32;
+    pos.beat_type = signature.den as f32;
+    pos.ticks_per_beat = TICKS_PER_BEAT;
+    // JACK counts beats of the beat type, the master tempo is in quarters
+    pos.beats_per_minute = timebase.settings.bpm / signature.beat_length();
+}
+
+/// Bar, beat and tick position as JACK counts them, starting at 1|1|0
+#[derive(Debug, PartialEq)]
+struct Bbt {
+    bar: i32,
+    beat: i32,
+    tick: i32,
+    bar_start_tick: f64,
+}
+
+impl Bbt {
+    fn at(quarter: f64, signature: TimeSignature) -> Bbt {
+        let beats = quarter.max(0.0) / signature.beat_length();
+        let bar = (beats / signature.num as f64).floor();
+        let beat = beats - bar * signature.num as f64;
+        Bbt {
+            bar: bar as i32 + 1,
+            beat: beat.floor() as i32 + 1,
+            tick: (beat.fract() * TICKS_PER_BEAT) as i32,
+            bar_start_tick: bar * signature.num as f64 * TICKS_PER_BEAT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bbt() {
+        let four_four = TimeSignature::new(4, 4);
+        assert_eq!(Bbt::at(0.0, four_four).bar, 1);
+        let bbt = Bbt::at(5.5, four_four);
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (2, 2, 960));
+        assert_eq!(bbt.bar_start_tick, 4.0 * TICKS_PER_BEAT);
+        // Eighths in 7/8, bar 2 starts after 3.5 quarters
+        let bbt = Bbt::at(4.0, TimeSignature::new(7, 8));
+        assert_eq!((bbt.bar, bbt.beat, bbt.tick), (2, 2, 0));
+    }
+}
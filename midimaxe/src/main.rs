@@ -1,5 +1,6 @@
 use std::io::stdout;
 
+use clap::Parser;
 use crossterm::terminal::{
     disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
 };
@@ -7,54 +8,195 @@ use crossterm::ExecutableCommand;
 
 use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
-use std::thread;
+use std::net::ToSocketAddrs;
+use std::path::PathBuf;
 use time::ext::NumericalStdDuration;
 
+#[cfg(target_os = "linux")]
+mod alsaseq;
+mod backend;
+mod eventlog;
+#[cfg(feature = "jack")]
+mod jackmidi;
+mod metrics;
 mod midisync;
 mod multisync;
+mod recording;
+mod rtpmidi;
+mod session;
+mod setlist;
+mod tempomap;
+mod tickloop;
+mod timing;
 mod ui;
 
-use multisync::MultiSyncCommand;
+use backend::{Backend, OutputOptions};
+use multisync::{MultiSyncCommand, Settings, DEFAULT_MAX_BPM, DEFAULT_MIN_BPM};
+use recording::Recording;
+use session::Session;
+use setlist::Setlist;
+use tempomap::TempoMap;
+use tickloop::TickLoop;
+use timing::TimingOptions;
 use ui::MultiSyncUi;
-use utils::programclock::{self, now};
+use utils::programclock;
+
+/// MIDI master clock for the synthie jam session
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Initial tempo in BPM
+    #[arg(long, default_value_t = 130.0)]
+    bpm: f64,
+
+    /// Lowest tempo that can be set
+    #[arg(long, default_value_t = DEFAULT_MIN_BPM)]
+    min_bpm: f64,
+
+    /// Highest tempo that can be set
+    #[arg(long, default_value_t = DEFAULT_MAX_BPM)]
+    max_bpm: f64,
+
+    /// Session file with scenes (TOML)
+    #[arg(long)]
+    session: Option<PathBuf>,
+
+    /// Setlist with songs (TOML)
+    #[arg(long)]
+    setlist: Option<PathBuf>,
+
+    /// Standard MIDI File whose tempo map drives the clock
+    #[arg(long)]
+    tempo_map: Option<PathBuf>,
+
+    /// Record tempo, transport and markers of the session to this MIDI file
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Directory for the session event log, rotated daily (JSON lines)
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+
+    /// Run the clock thread with SCHED_FIFO priority and locked memory (Linux)
+    #[arg(long)]
+    realtime: bool,
+
+    /// SCHED_FIFO priority of the clock thread
+    #[arg(long, default_value_t = 70)]
+    rt_priority: i32,
+
+    /// How clock messages are sent to the ports
+    #[arg(long, value_enum, default_value_t = Backend::Midir)]
+    backend: Backend,
+
+    /// Time in ms clock messages are queued ahead with timestamped backends
+    #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(0..=200))]
+    lookahead: u64,
+
+    /// Act as JACK transport timebase master with the tempo and position of the clock
+    #[arg(long)]
+    jack_timebase: bool,
+
+    /// Serve Prometheus metrics on http://127.0.0.1:<PORT>/metrics
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Run a network MIDI (RTP-MIDI) session on this control port, the data port is the one above
+    #[arg(long)]
+    rtp_port: Option<u16>,
+
+    /// Invite the network MIDI session at HOST:PORT (control port), can be given multiple times
+    #[arg(long)]
+    rtp_peer: Vec<String>,
+}
+
+const DEFAULT_RTP_PORT: u16 = 5004;
 
 fn main() {
-    /*tracing_subscriber::fmt()
-    .with_thread_ids(true)
-    .with_file(true)
-    .with_level(true)
-    .with_line_number(true)
-    .init();*/
-    match run() {
+    let args = Args::parse();
+    match run(args) {
         Ok(_) => (),
         Err(err) => println!("Error: {}", err),
     }
 }
 
-fn run() -> anyhow::Result<()> {
+fn run(args: Args) -> anyhow::Result<()> {
     programclock::now();
-    let (mut sync, cmd) = multisync::MultiSync::new()?;
+    let (log, _log_guard) = eventlog::init(args.log_dir.as_deref())?;
+    let settings = Settings::new(args.bpm, 16.0, None).with_bpm_limits(args.min_bpm, args.max_bpm);
+    settings.validate()?;
+    let session = match &args.session {
+        Some(path) => Session::load(path)?,
+        None => Session::default(),
+    };
+    let settings = settings.with_song_select(session.song_select);
+    let setlist = match &args.setlist {
+        Some(path) => Setlist::load(path)?,
+        None => Setlist::default(),
+    };
+    let tempo_map = match &args.tempo_map {
+        Some(path) => Some(TempoMap::load(path)?),
+        None => None,
+    };
+    let recording = args.record.as_deref().map(Recording::new);
+    let rtp = match (args.rtp_port, args.rtp_peer.is_empty()) {
+        (None, true) => None,
+        (port, _) => {
+            let mut peers = Vec::new();
+            for peer in args.rtp_peer.iter() {
+                let addr = peer
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or_else(|| anyhow::anyhow!("Invalid RTP-MIDI peer {:?}", peer))?;
+                peers.push(addr);
+            }
+            let port = port.unwrap_or(DEFAULT_RTP_PORT);
+            let session = rtpmidi::RtpSession::spawn("midimaxe", port, peers)?;
+            tracing::info!(port = session.port(), "RTP-MIDI session listening");
+            Some(session)
+        }
+    };
+    let (ticks, tick_ctrl) = TickLoop::new();
+    let (sync, cmd) = multisync::MultiSync::new(
+        settings,
+        session,
+        setlist,
+        tempo_map,
+        recording,
+        tick_ctrl,
+        OutputOptions {
+            backend: args.backend,
+            lookahead: args.lookahead.std_milliseconds(),
+        },
+        rtp,
+    )?;
     let (s, listener) = crossbeam_channel::unbounded::<multisync::MultiSyncEvent>();
     cmd.send(MultiSyncCommand::AddListener(s)).unwrap();
-    let mut ui = MultiSyncUi::new(cmd, listener);
-
-    let _t = thread::spawn(move || loop {
-        let sleep_duration = sync
-            .run()
-            .unwrap_or(None)
-            .and_then(|next_event| {
-                let current = now().0;
-                if current < next_event {
-                    Some(next_event - current)
-                } else {
-                    None
-                }
-            })
-            .unwrap_or(10.0.std_milliseconds());
-        if sleep_duration > 0.0.std_milliseconds() {
-            std::thread::sleep(sleep_duration);
-        }
-    });
+    let mut ui = MultiSyncUi::new(cmd.clone(), listener, log);
+    // Stop by themselves once the control thread is gone
+    #[cfg(feature = "jack")]
+    let _timebase = match args.jack_timebase {
+        true => Some(jackmidi::spawn_timebase(&cmd)?),
+        false => None,
+    };
+    #[cfg(not(feature = "jack"))]
+    if args.jack_timebase {
+        anyhow::bail!("midimaxe was built without JACK support");
+    }
+    let _metrics = match args.metrics_port {
+        Some(port) => Some(metrics::spawn(([127, 0, 0, 1], port).into(), &cmd)?),
+        None => None,
+    };
+
+    let control = sync.spawn()?;
+    let clock = timing::spawn(
+        ticks,
+        TimingOptions {
+            realtime: args.realtime,
+            priority: args.rt_priority,
+        },
+    )?;
 
     // Initialize console
     stdout().execute(EnterAlternateScreen)?;
@@ -79,5 +221,15 @@ fn run() -> anyhow::Result<()> {
     stdout().execute(LeaveAlternateScreen)?;
     disable_raw_mode()?;
 
+    // Stop all ports and write the recording before exiting
+    if cmd.send(MultiSyncCommand::Shutdown).is_ok() {
+        control
+            .join()
+            .map_err(|_| anyhow::anyhow!("Control thread panicked"))??;
+        clock
+            .join()
+            .map_err(|_| anyhow::anyhow!("Clock thread panicked"))?;
+    }
+
     Ok(())
 }
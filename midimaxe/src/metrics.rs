@@ -0,0 +1,274 @@
+use anyhow::{Context, Result};
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use time::ext::NumericalStdDuration;
+use tracing::{info, warn};
+use utils::programclock::now;
+
+use crate::midisync::MidiSyncState;
+use crate::multisync::{MultiSyncCommand, MultiSyncDisplay, MultiSyncEvent, MultiSyncState};
+use crate::timing::{Histogram, LATENESS_BUCKETS};
+
+const POLL: f64 = 50.0; // Interval in ms to check for connections and display updates
+const REQUEST_TIMEOUT: f64 = 1.0; // Seconds to wait for a client to send its request
+
+/* Serves the state of the session as Prometheus text metrics. The server only
+ * keeps the latest display update of the control thread, so scraping can never
+ * hold up the control or clock threads. It stops with the control thread. */
+pub fn spawn(addr: SocketAddr, cmd: &Sender<MultiSyncCommand>) -> Result<JoinHandle<()>> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    listener
+        .set_nonblocking(true)
+        .context("Failed to set up metrics listener")?;
+    let (s, events) = unbounded();
+    cmd.send(MultiSyncCommand::AddListener(s))
+        .context("Failed to subscribe to display updates")?;
+    info!(%addr, "Serving metrics");
+    thread::Builder::new()
+        .name("midimaxe metrics".to_owned())
+        .spawn(move || serve(listener, events))
+        .context("Failed to start metrics thread")
+}
+
+fn serve(listener: TcpListener, events: Receiver<MultiSyncEvent>) {
+    let mut disp = MultiSyncDisplay::default();
+    loop {
+        loop {
+            match events.try_recv() {
+                Ok(MultiSyncEvent::DisplayUpdate(update)) => disp = *update,
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, &disp) {
+                    warn!(error = ?e, "Failed to serve metrics");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL.std_milliseconds()),
+            Err(e) => {
+                warn!(error = ?e, "Failed to accept metrics connection");
+                thread::sleep(POLL.std_milliseconds())
+            }
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, disp: &MultiSyncDisplay) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT.std_seconds()))?;
+    let mut buf = [0u8; 1024];
+    let len = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/" | "/metrics" => ("200 OK", render(disp, now().0)),
+        _ => ("404 Not Found", "Not found\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// Metrics in the Prometheus text exposition format
+pub fn render(disp: &MultiSyncDisplay, uptime: Duration) -> String {
+    let mut out = String::new();
+    let running = match disp.state {
+        MultiSyncState::Started(time) => uptime.saturating_sub(time.0),
+        MultiSyncState::Stopped => Duration::ZERO,
+    };
+    header(
+        &mut out,
+        "uptime_seconds",
+        "gauge",
+        "Time since midimaxe started",
+    );
+    writeln!(out, "midimaxe_uptime_seconds {}", uptime.as_secs_f64()).unwrap();
+    header(
+        &mut out,
+        "running_seconds",
+        "gauge",
+        "Time since the clock started, 0 when stopped",
+    );
+    writeln!(out, "midimaxe_running_seconds {}", running.as_secs_f64()).unwrap();
+    header(&mut out, "tempo_bpm", "gauge", "Current master tempo");
+    writeln!(out, "midimaxe_tempo_bpm {}", disp.settings.bpm).unwrap();
+
+    header(&mut out, "ports", "gauge", "Number of ports by sync state");
+    for state in ["disconnected", "stopped", "starting", "running", "error"] {
+        let count = disp
+            .ports
+            .iter()
+            .filter(|p| state_label(&p.state) == state)
+            .count();
+        writeln!(out, "midimaxe_ports{{state=\"{}\"}} {}", state, count).unwrap();
+    }
+
+    header(
+        &mut out,
+        "clock_lateness_seconds",
+        "gauge",
+        "Lateness of the clock thread",
+    );
+    for (stat, value) in [
+        ("mean", disp.lateness.mean),
+        ("p99", disp.lateness.p99),
+        ("max", disp.lateness.max),
+    ] {
+        writeln!(
+            out,
+            "midimaxe_clock_lateness_seconds{{stat=\"{}\"}} {}",
+            stat,
+            value.as_secs_f64()
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "ticks_total",
+        "counter",
+        "Clock ticks sent per port",
+    );
+    for port in disp.ports.iter() {
+        if let Some(metrics) = port.metrics {
+            let label = escape(&port.info.name);
+            let count = metrics.histogram.count;
+            writeln!(out, "midimaxe_ticks_total{{port=\"{}\"}} {}", label, count).unwrap();
+        }
+    }
+    header(
+        &mut out,
+        "tick_lateness_seconds",
+        "histogram",
+        "Lateness of clock ticks per port",
+    );
+    for port in disp.ports.iter() {
+        if let Some(metrics) = port.metrics {
+            histogram(&mut out, &escape(&port.info.name), &metrics.histogram);
+        }
+    }
+    header(
+        &mut out,
+        "port_errors_total",
+        "counter",
+        "Failures per port",
+    );
+    for port in disp.ports.iter() {
+        let label = escape(&port.info.name);
+        writeln!(
+            out,
+            "midimaxe_port_errors_total{{port=\"{}\"}} {}",
+            label, port.errors
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "command_errors_total",
+        "counter",
+        "Commands that failed",
+    );
+    writeln!(out, "midimaxe_command_errors_total {}", disp.command_errors).unwrap();
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP midimaxe_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE midimaxe_{} {}", name, kind).unwrap();
+}
+
+// Prometheus buckets are cumulative
+fn histogram(out: &mut String, port: &str, histogram: &Histogram) {
+    let name = "midimaxe_tick_lateness_seconds";
+    let mut count = 0;
+    for (le, n) in LATENESS_BUCKETS.iter().zip(histogram.buckets.iter()) {
+        count += n;
+        writeln!(
+            out,
+            "{}_bucket{{port=\"{}\",le=\"{}\"}} {}",
+            name, port, le, count
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "{}_bucket{{port=\"{}\",le=\"+Inf\"}} {}",
+        name, port, histogram.count
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "{}_sum{{port=\"{}\"}} {}",
+        name,
+        port,
+        histogram.sum.as_secs_f64()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "{}_count{{port=\"{}\"}} {}",
+        name, port, histogram.count
+    )
+    .unwrap();
+}
+
+fn state_label(state: &Option<MidiSyncState>) -> &'static str {
+    match state {
+        None => "disconnected",
+        Some(MidiSyncState::Stopped) => "stopped",
+        Some(MidiSyncState::Starting) => "starting",
+        Some(MidiSyncState::Running) => "running",
+        Some(MidiSyncState::Error(_)) => "error",
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let out = render(&MultiSyncDisplay::default(), Duration::from_secs(90));
+        assert!(out.contains("midimaxe_uptime_seconds 90\n"));
+        assert!(out.contains("midimaxe_running_seconds 0\n"));
+        assert!(out.contains("midimaxe_tempo_bpm 130\n"));
+        assert!(out.contains("midimaxe_ports{state=\"running\"} 0\n"));
+        assert!(out.contains("# TYPE midimaxe_tick_lateness_seconds histogram\n"));
+
+        let mut hist = Histogram::default();
+        for us in [50, 300, 300, 100_000] {
+            hist.add(Duration::from_micros(us));
+        }
+        let mut out = String::new();
+        histogram(&mut out, &escape("USB \"A\""), &hist);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "midimaxe_tick_lateness_seconds_bucket{port=\"USB \\\"A\\\"\",le=\"0.0001\"} 1"
+        );
+        assert!(lines[2].ends_with("le=\"0.0005\"} 3"));
+        assert!(lines[7].ends_with("le=\"0.025\"} 3"));
+        assert!(lines[8].ends_with("le=\"+Inf\"} 4"));
+        assert!(lines[10].ends_with("_count{port=\"USB \\\"A\\\"\"} 4"));
+    }
+}
@@ -1,10 +1,15 @@
 use anyhow::{bail, Context, Result};
-use midir::MidiOutputConnection;
 use std::time::Duration;
 use time::ext::NumericalStdDuration;
-use utils::midimessages::{MIDI_CLOCK, MIDI_START, MIDI_STOP};
+use utils::midimessages::{
+    song_position_pointer, MtcRate, Timecode, MIDI_ACTIVE_SENSING, MIDI_CLOCK, MIDI_CONTINUE,
+    MIDI_START, MIDI_STOP,
+};
 use utils::programclock::now;
 
+use crate::backend::MidiOut;
+use crate::timing::{Histogram, Lateness, SyncMetrics};
+
 #[derive(Debug, Clone)]
 pub enum MidiSyncState {
     Stopped,
@@ -13,56 +18,328 @@ pub enum MidiSyncState {
     Error(String),
 }
 
+/// Rational multiplier applied to the master tempo of a single port,
+/// e.g. 1/2 for a device that should run at half-time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub num: u32,
+    pub den: u32,
+}
+
+/// What a port follows, the beat clock or the time line
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SyncMode {
+    #[default]
+    Clock,
+    /// MIDI Time Code quarter frames, with a full frame at the start
+    Timecode(MtcRate),
+}
+
+/// What a port sends while no clock is running, for devices that expect a steady stream
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Heartbeat {
+    #[default]
+    Off,
+    /// Active Sensing whenever nothing else was sent for a while
+    Sensing,
+    /// MIDI clock at the port tempo, for devices that lose their tempo when the clock pauses
+    Clock,
+}
+
+/// Note played as count-in click before a scheduled start
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cue {
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+    pub scope: CueScope,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CueScope {
+    /// Count in before this port starts
+    Port,
+    /// Count in before any port starts, for a click device in the room
+    Global,
+}
+
+/// Tempo change that takes effect at a point on the master beat grid
+#[derive(Debug, Clone, Copy)]
+struct TempoChange {
+    at: Duration,
+    bpm: f64,
+    tpqn: f64,
+}
+
+/// Clock state before a start or tick was handed to the port, restored when it is cancelled
+#[derive(Debug, Clone, Copy)]
+struct ClockSnapshot {
+    running: bool,
+    start_time: Option<Duration>,
+    next_clk: Option<Duration>,
+    anchor: Option<Duration>,
+    ticks: u64,
+    bpm: f64,
+    tpqn: f64,
+    pending_tempo: Option<TempoChange>,
+}
+
+/// Clock or time code message, fixed in size so sending ticks never allocates
+#[derive(Debug, Clone, Copy)]
+struct ClockMsg {
+    len: usize,
+    bytes: [u8; 10],
+}
+
+/// Message waiting for its time
+#[derive(Debug)]
+struct Scheduled {
+    time: Duration,
+    msg: Vec<u8>,
+    batch: Option<Batch>,
+}
+
+/// Messages that are taken back together
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Batch {
+    /// Clicks counting in to the start at this time
+    CountIn(Duration),
+    /// Program changes of a scene that was not reached yet
+    Scene,
+}
+
+/// Message handed to the port ahead of its time
+#[derive(Debug)]
+enum Queued {
+    Clock(Duration, ClockMsg, ClockSnapshot),
+    Message(Scheduled),
+}
+
 pub struct MidiSync {
     start_time: Option<Duration>,
     next_clk: Option<Duration>,
+    anchor: Option<Duration>, // Time of tick 0, moved on tempo changes
+    ticks: u64,               // Ticks sent since anchor
     bpm: f64,
     tpqn: f64,
+    rate: Rate,
+    mode: SyncMode,
+    heartbeat: Heartbeat,
+    last_sent: Duration, // Time the last message handed to the port is delivered
+    pending_tempo: Option<TempoChange>,
+    position: Option<u16>, // Song position to continue from instead of starting
+    timeline: Duration,    // Time code at the start time
     state: MidiSyncState,
-    port: MidiOutputConnection,
+    port: Box<dyn MidiOut>,
+    lookahead: Duration, // Messages are handed to the port this long before they are due
+    scheduled: Vec<Scheduled>, // Sorted by time, earliest first
+    queued: Vec<Queued>, // Handed to the port, but not due yet
+    lateness: Lateness,  // Actual versus planned hand over of ticks to the port
+    histogram: Histogram, // Lateness of all ticks since connecting
 }
 
-const DEFAULT_TPQN: f64 = 24.0;
+pub const DEFAULT_TPQN: f64 = 24.0;
+const CUE_LENGTH: f64 = 0.1; // Maximum length of a count-in click in seconds
+const LATENESS_WINDOW: usize = 24 * 4 * 8; // Eight bars of ticks at the default resolution
+const SENSING_INTERVAL: f64 = 0.27; // Receivers expect a message at least every 300 ms
 
 impl MidiSync {
-    pub fn new(port: MidiOutputConnection, bpm: f64, tpqn: Option<f64>) -> MidiSync {
+    /// Ports with timestamped delivery get all messages up to lookahead ahead of time
+    pub fn new(
+        port: Box<dyn MidiOut>,
+        lookahead: Duration,
+        bpm: f64,
+        tpqn: Option<f64>,
+        rate: Rate,
+        mode: SyncMode,
+        heartbeat: Heartbeat,
+    ) -> MidiSync {
         MidiSync {
+            lookahead: match port.timestamped() {
+                true => lookahead,
+                false => Duration::ZERO,
+            },
             start_time: None,
             next_clk: None,
+            anchor: None,
+            ticks: 0,
             bpm,
             tpqn: tpqn.unwrap_or(DEFAULT_TPQN),
+            rate,
+            mode,
+            heartbeat,
+            last_sent: now().0,
+            pending_tempo: None,
+            position: None,
+            timeline: Duration::ZERO,
             state: MidiSyncState::Stopped,
             port,
+            scheduled: Vec::new(),
+            queued: Vec::new(),
+            lateness: Lateness::new(LATENESS_WINDOW),
+            histogram: Histogram::default(),
         }
     }
 
-    pub fn start(&mut self, start_time: Option<Duration>) {
-        match self.state {
-            MidiSyncState::Stopped => {
-                self.start_time = Some(start_time.unwrap_or_else(|| now().0));
-                self.next_clk = self.start_time.clone();
-                self.state = MidiSyncState::Starting;
-            }
-            _ => (),
+    /// Start at start_time on the beat grid, with the time code at timeline
+    pub fn start(&mut self, start_time: Option<Duration>, timeline: Duration) {
+        if let MidiSyncState::Stopped = self.state {
+            self.start_time = Some(self.retimed(start_time.unwrap_or_else(|| now().0)));
+            self.next_clk = self.start_time;
+            self.anchor = self.start_time;
+            self.ticks = 0;
+            self.position = None;
+            self.timeline = timeline;
+            self.lateness.clear();
+            self.state = MidiSyncState::Starting;
+        }
+    }
+
+    /* Continue from a song position (in sixteenths) instead of starting from the
+     * beginning. The pointer is sent right away so the device has time to locate
+     * before the MIDI_CONTINUE at the start time. */
+    pub fn locate(&mut self, sixteenths: u16) {
+        if let (MidiSyncState::Starting, SyncMode::Clock) = (&self.state, self.mode) {
+            self.position = Some(sixteenths);
+            self.schedule(now().0, song_position_pointer(sixteenths).to_vec());
         }
     }
 
     pub fn run(&mut self) -> Option<Duration> {
-        let result: Result<()> = match &self.state {
-            MidiSyncState::Starting => self.run_starting(),
-            MidiSyncState::Running => self.run_running(),
-            _ => Ok(()),
-        };
+        // A failed port has nothing to send until it is replaced
+        if let MidiSyncState::Error(_) = self.state {
+            return None;
+        }
+        let result: Result<()> = self
+            .run_scheduled()
+            .and_then(|_| match &self.state {
+                MidiSyncState::Starting => self.run_starting(),
+                MidiSyncState::Running => self.run_running(),
+                _ => Ok(()),
+            })
+            .and_then(|_| self.run_heartbeat());
         match result {
             // TODO: Change BPM to timeline here
             Err(e) => {
-                self.state = MidiSyncState::Error(e.to_string());
+                self.fail(e.to_string());
                 None
             }
-            _ => self.next_clk,
+            _ => match (self.next_clk, self.scheduled.first()) {
+                (Some(clk), Some(s)) => Some(clk.min(s.time)),
+                (clk, scheduled) => clk.or(scheduled.map(|s| s.time)),
+            }
+            .map(|t| t.saturating_sub(self.lookahead))
+            // Heartbeats are sent right away, not ahead of time
+            .into_iter()
+            .chain(self.next_heartbeat())
+            .min(),
         }
     }
 
+    /// Send a message at the given time, independent of the clock state
+    pub fn schedule(&mut self, time: Duration, msg: Vec<u8>) {
+        self.insert(Scheduled {
+            time,
+            msg,
+            batch: None,
+        });
+    }
+
+    /// Schedule a message of a scene, it is dropped again if another scene is recalled
+    pub fn schedule_scene(&mut self, time: Duration, msg: Vec<u8>) {
+        self.insert(Scheduled {
+            time,
+            msg,
+            batch: Some(Batch::Scene),
+        });
+    }
+
+    /// Drop the messages of a scene that were not sent yet
+    pub fn cancel_scene(&mut self) {
+        self.cancel_batch(Batch::Scene);
+    }
+
+    /* A time on the beat grid as it was before the pending tempo change, moved to where
+     * it is after the change, the same way retime moves everything already scheduled. */
+    fn retimed(&self, t: Duration) -> Duration {
+        match self.pending_tempo {
+            Some(tempo) if t > tempo.at => tempo.at + (t - tempo.at).mul_f64(self.bpm / tempo.bpm),
+            _ => t,
+        }
+    }
+
+    fn insert(&mut self, scheduled: Scheduled) {
+        let idx = self.scheduled.partition_point(|s| s.time <= scheduled.time);
+        self.scheduled.insert(idx, scheduled);
+    }
+
+    /* Schedule count-in clicks for the last bar (beats of length beat) before start_time
+     * on the beat grid. The clicks are tagged with that time, even once a tempo change
+     * moves them. */
+    pub fn count_in(&mut self, start_time: Duration, beat: Duration, beats: u32, cue: &Cue) {
+        let length = beat.mul_f64(0.5).min(CUE_LENGTH.std_seconds());
+        let channel = cue.channel & 0x0F;
+        let current = now().0;
+        for beats in 1..=beats {
+            let Some(t) = start_time.checked_sub(beat * beats) else {
+                continue;
+            };
+            let t = self.retimed(t);
+            let note_on = vec![0x90 | channel, cue.note, cue.velocity];
+            let already_scheduled = self
+                .scheduled
+                .iter()
+                .any(|s| s.time == t && s.msg == note_on);
+            if t < current || already_scheduled {
+                continue;
+            }
+            for (time, msg) in [
+                (t, note_on),
+                (t + length, vec![0x80 | channel, cue.note, 0]),
+            ] {
+                self.insert(Scheduled {
+                    time,
+                    msg,
+                    batch: Some(Batch::CountIn(start_time)),
+                });
+            }
+        }
+    }
+
+    /// Drop the clicks that were not played yet of the count-in for start_time
+    pub fn cancel_count_in(&mut self, start_time: Duration) {
+        self.cancel_batch(Batch::CountIn(start_time));
+    }
+
+    /* Take back the messages of a batch that were not sent yet, even if they
+     * were handed to the port already. Note offs stay, so no note keeps hanging. */
+    fn cancel_batch(&mut self, batch: Batch) {
+        let pending = |s: &Scheduled| s.batch == Some(batch) && !is_note_off(&s.msg);
+        let current = now().0;
+        let queued = self.queued.iter().any(|q| match q {
+            Queued::Message(s) => pending(s) && s.time > current,
+            Queued::Clock(..) => false,
+        });
+        if queued {
+            if let Err(e) = self.rollback(current) {
+                self.fail(e.to_string());
+                return;
+            }
+        }
+        self.scheduled.retain(|s| !pending(s));
+    }
+
+    /* Mark the port as failed, e.g. because a send failed or it could not be opened
+     * again. Nothing pending is sent anymore, so there is no deadline to wake up for. */
+    pub fn fail(&mut self, error: String) {
+        self.state = MidiSyncState::Error(error);
+        self.start_time = None;
+        self.next_clk = None;
+        self.pending_tempo = None;
+        self.scheduled.clear();
+        self.queued.clear();
+    }
+
     pub fn stop(&mut self) {
         match self.state {
             MidiSyncState::Running | MidiSyncState::Starting | MidiSyncState::Stopped => {
@@ -70,24 +347,38 @@ impl MidiSync {
                 // and should provide users with an easy way to stop
                 let result = self
                     .port
-                    .send(&MIDI_STOP)
-                    .context("Failed to send MIDI_STOP message");
-                self.state = match result {
-                    Ok(_) => MidiSyncState::Stopped,
-                    Err(e) => MidiSyncState::Error(e.to_string()),
-                };
+                    .cancel()
+                    .and_then(|_| self.port.send(&MIDI_STOP))
+                    .context("Failed to send MIDI_STOP message")
+                    .map(|_| self.last_sent = now().0)
+                    .and_then(|_| self.cancel_scheduled());
                 self.start_time = None;
                 self.next_clk = None;
+                match result {
+                    Ok(_) => self.state = MidiSyncState::Stopped,
+                    Err(e) => self.fail(e.to_string()),
+                }
             }
             _ => (),
         }
     }
 
-    pub fn update(&mut self, bpm: f64, tpqn: Option<f64>) -> Result<()> {
+    pub fn update(
+        &mut self,
+        bpm: f64,
+        tpqn: Option<f64>,
+        rate: Rate,
+        mode: SyncMode,
+        heartbeat: Heartbeat,
+    ) -> Result<()> {
         match self.state {
             MidiSyncState::Stopped => {
                 self.bpm = bpm;
                 self.tpqn = tpqn.unwrap_or(DEFAULT_TPQN);
+                self.rate = rate;
+                self.mode = mode;
+                self.heartbeat = heartbeat;
+                self.pending_tempo = None;
                 Ok(())
             }
             _ => bail!(
@@ -97,18 +388,108 @@ impl MidiSync {
         }
     }
 
+    /* Change tempo at a point on the beat grid without stopping. Everything that is
+     * scheduled after that point (start, messages) keeps its position in beats. */
+    pub fn retime(&mut self, at: Duration, bpm: f64, tpqn: Option<f64>) {
+        if let Err(e) = self.rollback(at) {
+            self.fail(e.to_string());
+            return;
+        }
+        let old_bpm = self.pending_tempo.map(|p| p.bpm).unwrap_or(self.bpm);
+        let scale = |t: Duration| {
+            if t > at {
+                at + (t - at).mul_f64(old_bpm / bpm)
+            } else {
+                t
+            }
+        };
+        if let MidiSyncState::Starting = self.state {
+            self.start_time = self.start_time.map(scale);
+            self.anchor = self.start_time;
+            self.next_clk = self.start_time;
+        }
+        self.scheduled
+            .iter_mut()
+            .for_each(|s| s.time = scale(s.time));
+        self.pending_tempo = Some(TempoChange {
+            at,
+            bpm,
+            tpqn: tpqn.unwrap_or(DEFAULT_TPQN),
+        });
+    }
+
     pub fn state(&self) -> MidiSyncState {
         self.state.clone()
     }
 
+    pub fn start_time(&self) -> Option<Duration> {
+        self.start_time
+    }
+
+    pub fn metrics(&mut self) -> SyncMetrics {
+        SyncMetrics {
+            lateness: self.lateness.summary(),
+            histogram: self.histogram,
+        }
+    }
+
+    fn run_scheduled(&mut self) -> Result<()> {
+        let current = now().0;
+        self.queued.retain(|q| q.time() > current);
+        let due = self
+            .scheduled
+            .partition_point(|s| s.time <= current + self.lookahead);
+        for scheduled in self.scheduled.drain(..due) {
+            self.port
+                .send_at(scheduled.time, &scheduled.msg)
+                .context("Failed to send scheduled message")?;
+            self.last_sent = self.last_sent.max(scheduled.time);
+            if scheduled.time > current {
+                self.queued.push(Queued::Message(scheduled));
+            }
+        }
+        Ok(())
+    }
+
+    /* Drop all pending messages, but make sure no note keeps hanging
+     * because its note off was dropped */
+    fn cancel_scheduled(&mut self) -> Result<()> {
+        let queued = self.queued.drain(..).filter_map(|q| match q {
+            Queued::Message(scheduled) => Some(scheduled),
+            Queued::Clock(..) => None,
+        });
+        for scheduled in queued.chain(self.scheduled.drain(..)) {
+            if is_note_off(&scheduled.msg) {
+                self.port
+                    .send(&scheduled.msg)
+                    .context("Failed to send pending note off")?;
+            }
+        }
+        Ok(())
+    }
+
     fn run_starting(&mut self) -> Result<()> {
         let start_time = self
             .start_time
             .context("BUG: start_time == None unexpected in Starting state")?;
-        if start_time <= now().0 {
-            self.port
-                .send(&MIDI_START)
-                .context("Failed to send MIDI_START message")?;
+        if start_time <= now().0 + self.lookahead {
+            let snapshot = self.snapshot();
+            if let Some(tempo) = self.pending_tempo.filter(|t| t.at <= start_time) {
+                self.apply_tempo(tempo, start_time);
+            }
+            match (self.mode, self.position) {
+                (SyncMode::Timecode(rate), _) => {
+                    let timecode = Timecode::from_duration(self.timeline, rate);
+                    self.send_clock(start_time, &timecode.full_frame(), snapshot)
+                        .context("Failed to send MTC full frame")?
+                }
+                (SyncMode::Clock, Some(_)) => self
+                    .send_clock(start_time, &MIDI_CONTINUE, snapshot)
+                    .context("Failed to send MIDI_CONTINUE message")?,
+                (SyncMode::Clock, None) => self
+                    .send_clock(start_time, &MIDI_START, snapshot)
+                    .context("Failed to send MIDI_START message")?,
+            }
             self.state = MidiSyncState::Running;
             self.run_running()
         } else {
@@ -116,16 +497,524 @@ impl MidiSync {
         }
     }
 
+    // Send all ticks up to the end of the lookahead window
     fn run_running(&mut self) -> Result<()> {
-        let next_clk = self
-            .next_clk
-            .context("BUG: next_clk == None unexpected in Running state")?;
-        if next_clk <= now().0 {
-            self.port
+        let horizon = now().0 + self.lookahead;
+        loop {
+            let next_clk = self
+                .next_clk
+                .context("BUG: next_clk == None unexpected in Running state")?;
+            // Tempo changes happen on the beat grid, snap to it if the next tick is close
+            let tempo = self
+                .pending_tempo
+                .filter(|t| next_clk + self.tick_duration() / 2 >= t.at);
+            let tick_time = match (self.mode, tempo) {
+                (SyncMode::Clock, Some(tempo)) => tempo.at,
+                _ => next_clk,
+            };
+            if tick_time > horizon {
+                return Ok(());
+            }
+            let snapshot = self.snapshot();
+            match (self.mode, tempo) {
+                (SyncMode::Clock, Some(tempo)) => self.apply_tempo(tempo, tempo.at),
+                // Time code runs in real time, the tempo only matters once stopped
+                (SyncMode::Timecode(_), Some(tempo)) => {
+                    self.bpm = tempo.bpm;
+                    self.tpqn = tempo.tpqn;
+                    self.pending_tempo = None;
+                }
+                (_, None) => (),
+            }
+            match self.mode {
+                SyncMode::Clock => self
+                    .send_clock(tick_time, &MIDI_CLOCK, snapshot)
+                    .context("Failed to send MIDI_CLOCK message")?,
+                SyncMode::Timecode(rate) => {
+                    // Eight quarter frames carry the position of their first frame, two frames
+                    let first = (self.timeline.as_secs_f64() * rate.fps()) as u64;
+                    let timecode = Timecode::from_frames(first + self.ticks / 8 * 2, rate);
+                    let msg = timecode.quarter_frame((self.ticks % 8) as u8);
+                    self.send_clock(tick_time, &msg, snapshot)
+                        .context("Failed to send MTC quarter frame")?
+                }
+            }
+            // Ticks are due at the port a lookahead before their time
+            let lateness = now()
+                .0
+                .saturating_sub(tick_time.saturating_sub(self.lookahead));
+            self.lateness.add(lateness);
+            self.histogram.add(lateness);
+            self.ticks += 1;
+            self.next_clk = Some(self.tick_time(self.ticks)?);
+        }
+    }
+
+    fn send_clock(&mut self, time: Duration, msg: &[u8], snapshot: ClockSnapshot) -> Result<()> {
+        self.port.send_at(time, msg)?;
+        self.last_sent = self.last_sent.max(time);
+        if time > now().0 {
+            self.queued
+                .push(Queued::Clock(time, ClockMsg::new(msg), snapshot));
+        }
+        Ok(())
+    }
+
+    fn run_heartbeat(&mut self) -> Result<()> {
+        let current = now().0;
+        if self.next_heartbeat().is_none_or(|t| t > current) {
+            return Ok(());
+        }
+        match self.heartbeat {
+            Heartbeat::Off => (),
+            Heartbeat::Sensing => self
+                .port
+                .send(&MIDI_ACTIVE_SENSING)
+                .context("Failed to send Active Sensing message")?,
+            Heartbeat::Clock => self
+                .port
                 .send(&MIDI_CLOCK)
-                .context("Failed to send MIDI_CLOCK message")?;
-            self.next_clk = Some(next_clk + (1.0 / (self.bpm * self.tpqn)).std_minutes());
+                .context("Failed to send MIDI_CLOCK heartbeat")?,
+        }
+        self.last_sent = self.last_sent.max(current);
+        Ok(())
+    }
+
+    /* Heartbeats fill the silence until the clock runs. Once started they stop
+     * before the start time, idle clock ticks are spaced so the last one is a
+     * whole tick before the start and the device keeps a steady tempo. */
+    fn next_heartbeat(&self) -> Option<Duration> {
+        if matches!(self.state, MidiSyncState::Error(_)) {
+            return None;
+        }
+        let next = match self.heartbeat {
+            Heartbeat::Off => return None,
+            Heartbeat::Sensing => self.last_sent + SENSING_INTERVAL.std_seconds(),
+            Heartbeat::Clock => {
+                let tick = (1.0 / (self.bpm * self.tpqn * self.rate.factor())).std_minutes();
+                match self.start_time {
+                    None => self.last_sent + tick,
+                    Some(start) => {
+                        let before = start.checked_sub(self.last_sent)?;
+                        let ticks = (before.as_secs_f64() / tick.as_secs_f64()).ceil() - 1.0;
+                        start.checked_sub(tick.mul_f64(ticks.max(0.0)))?
+                    }
+                }
+            }
+        };
+        match self.start_time {
+            Some(start) if next >= start => None,
+            _ => Some(next),
+        }
+    }
+
+    /* Take back everything queued for at or later, e.g. because the tempo changes
+     * inside the lookahead window. Messages go back to the schedule and the clock
+     * is reset to the first start or tick that was taken back. */
+    fn rollback(&mut self, at: Duration) -> Result<()> {
+        let current = now().0;
+        self.queued.retain(|q| q.time() > current);
+        if !self.queued.iter().any(|q| q.time() >= at) {
+            return Ok(());
+        }
+        // Ports drop everything on cancel, the messages before at are queued again
+        self.port.cancel()?;
+        let mut restore: Option<(Duration, ClockSnapshot)> = None;
+        for queued in std::mem::take(&mut self.queued) {
+            match queued {
+                q if q.time() < at => {
+                    self.port.send_at(q.time(), q.msg())?;
+                    self.queued.push(q);
+                }
+                Queued::Message(scheduled) => self.insert(scheduled),
+                Queued::Clock(time, _, snapshot) => {
+                    if restore.is_none_or(|(first, _)| time < first) {
+                        restore = Some((time, snapshot));
+                    }
+                }
+            }
+        }
+        if let Some((_, snapshot)) = restore {
+            self.restore(snapshot);
         }
         Ok(())
     }
+
+    fn snapshot(&self) -> ClockSnapshot {
+        ClockSnapshot {
+            running: matches!(self.state, MidiSyncState::Running),
+            start_time: self.start_time,
+            next_clk: self.next_clk,
+            anchor: self.anchor,
+            ticks: self.ticks,
+            bpm: self.bpm,
+            tpqn: self.tpqn,
+            pending_tempo: self.pending_tempo,
+        }
+    }
+
+    fn restore(&mut self, snapshot: ClockSnapshot) {
+        self.state = match snapshot.running {
+            true => MidiSyncState::Running,
+            false => MidiSyncState::Starting,
+        };
+        self.start_time = snapshot.start_time;
+        self.next_clk = snapshot.next_clk;
+        self.anchor = snapshot.anchor;
+        self.ticks = snapshot.ticks;
+        self.bpm = snapshot.bpm;
+        self.tpqn = snapshot.tpqn;
+        self.pending_tempo = snapshot.pending_tempo;
+    }
+
+    fn apply_tempo(&mut self, tempo: TempoChange, anchor: Duration) {
+        self.bpm = tempo.bpm;
+        self.tpqn = tempo.tpqn;
+        self.anchor = Some(anchor);
+        self.ticks = 0;
+        self.pending_tempo = None;
+    }
+
+    fn tick_duration(&self) -> Duration {
+        (1.0 / self.ticks_per_minute()).std_minutes()
+    }
+
+    // Time code ticks are quarter frames
+    fn ticks_per_minute(&self) -> f64 {
+        match self.mode {
+            SyncMode::Clock => self.bpm * self.tpqn * self.rate.factor(),
+            SyncMode::Timecode(rate) => rate.fps() * 4.0 * 60.0,
+        }
+    }
+
+    /* Ticks are always calculated from the anchor instead of accumulating
+     * tick durations. This keeps every port locked to the master beat grid,
+     * independent of its resolution and rate. */
+    fn tick_time(&self, tick: u64) -> Result<Duration> {
+        let anchor = self
+            .anchor
+            .context("BUG: anchor == None unexpected when calculating tick time")?;
+        Ok(anchor + (tick as f64 / self.ticks_per_minute()).std_minutes())
+    }
+}
+
+impl Queued {
+    fn time(&self) -> Duration {
+        match self {
+            Queued::Clock(time, ..) => *time,
+            Queued::Message(scheduled) => scheduled.time,
+        }
+    }
+
+    fn msg(&self) -> &[u8] {
+        match self {
+            Queued::Clock(_, msg, _) => msg.as_slice(),
+            Queued::Message(scheduled) => &scheduled.msg,
+        }
+    }
+}
+
+impl ClockMsg {
+    fn new(msg: &[u8]) -> ClockMsg {
+        let mut bytes = [0; 10];
+        let len = msg.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&msg[..len]);
+        ClockMsg { len, bytes }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+fn is_note_off(msg: &[u8]) -> bool {
+    msg.first().is_some_and(|status| status & 0xF0 == 0x80)
+}
+
+impl Rate {
+    pub fn new(num: u32, den: u32) -> Rate {
+        Rate { num, den }
+    }
+
+    pub fn factor(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+}
+
+impl Default for Rate {
+    fn default() -> Self {
+        Rate::new(1, 1)
+    }
+}
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.den == 1 {
+            write!(f, "x{}", self.num)
+        } else {
+            write!(f, "x{}/{}", self.num, self.den)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Queue = Arc<Mutex<Vec<(Duration, Vec<u8>)>>>;
+
+    // Timestamped port that keeps everything that was handed over and not cancelled
+    #[derive(Clone, Default)]
+    struct QueuePort(Queue);
+
+    impl MidiOut for QueuePort {
+        fn send(&mut self, msg: &[u8]) -> Result<()> {
+            self.send_at(now().0, msg)
+        }
+
+        fn timestamped(&self) -> bool {
+            true
+        }
+
+        fn send_at(&mut self, time: Duration, msg: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().push((time, msg.to_vec()));
+            Ok(())
+        }
+
+        fn cancel(&mut self) -> Result<()> {
+            let current = now().0;
+            self.0.lock().unwrap().retain(|(t, _)| *t <= current);
+            Ok(())
+        }
+    }
+
+    fn clock_times(port: &QueuePort, start: Duration) -> Vec<u64> {
+        let queue = port.0.lock().unwrap();
+        let mut times: Vec<u64> = queue
+            .iter()
+            .filter(|(_, msg)| *msg == MIDI_CLOCK)
+            .map(|(t, _)| (*t - start).as_micros() as u64)
+            .collect();
+        times.sort();
+        times
+    }
+
+    const LOOKAHEAD: Duration = Duration::from_millis(500);
+
+    fn queue_sync(port: &QueuePort, bpm: f64, mode: SyncMode, heartbeat: Heartbeat) -> MidiSync {
+        MidiSync::new(
+            Box::new(port.clone()),
+            LOOKAHEAD,
+            bpm,
+            None,
+            Rate::default(),
+            mode,
+            heartbeat,
+        )
+    }
+
+    #[test]
+    fn test_lookahead_rollback() {
+        let port = QueuePort::default();
+        // 60 BPM at 24 TPQN, a tick every 41.67 ms
+        let mut sync = queue_sync(&port, 60.0, SyncMode::Clock, Heartbeat::Off);
+        let start = now().0 + Duration::from_millis(100);
+        sync.start(Some(start), Duration::ZERO);
+        let before = now().0 + LOOKAHEAD - start;
+        sync.run();
+        let after = now().0 + LOOKAHEAD - start;
+        // Every tick up to the horizon seen by run() is queued, and nothing past it
+        let grid = |k: usize| k as u64 * 1_000_000 / 24;
+        let ticks = clock_times(&port, start);
+        let expected: Vec<u64> = (0..ticks.len()).map(grid).collect();
+        assert_eq!(ticks, expected);
+        assert!(ticks.len() >= 8);
+        assert!(*ticks.last().unwrap() <= after.as_micros() as u64);
+        assert!(grid(ticks.len()) > before.as_micros() as u64);
+
+        // Double time from 200 ms after the start, inside the queued window
+        sync.retime(start + Duration::from_millis(200), 120.0, None);
+        sync.run();
+        let ticks = clock_times(&port, start);
+        assert_eq!(&ticks[..5], &[0, 41_666, 83_333, 125_000, 166_666]);
+        assert_eq!(&ticks[5..8], &[200_000, 220_833, 241_666]);
+
+        // Stopping drops everything that is not due yet
+        sync.stop();
+        let queue = port.0.lock().unwrap();
+        assert!(queue.iter().all(|(t, _)| *t <= now().0));
+        assert_eq!(queue.last().unwrap().1, MIDI_STOP);
+    }
+
+    #[test]
+    fn test_timecode() {
+        let port = QueuePort::default();
+        let mut sync = queue_sync(
+            &port,
+            60.0,
+            SyncMode::Timecode(MtcRate::Fps25),
+            Heartbeat::Off,
+        );
+        let start = now().0 + Duration::from_millis(100);
+        sync.start(Some(start), Duration::from_millis(3_723_500));
+        sync.locate(32);
+        sync.run();
+        let queue = port.0.lock().unwrap();
+        // No song position, the full frame is the locate
+        assert_eq!(
+            queue[0],
+            (
+                start,
+                vec![0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 2, 3, 12, 0xF7]
+            )
+        );
+        // A quarter frame every 10 ms, the second group is two frames later
+        assert_eq!(queue[1], (start, vec![0xF1, 0x0C]));
+        assert_eq!(queue[2].0 - start, Duration::from_millis(10));
+        let pieces: Vec<u8> = queue[1..17].iter().map(|(_, msg)| msg[1]).collect();
+        assert_eq!(pieces[..2], [0x0C, 0x10]);
+        assert_eq!(pieces[7..10], [0x72, 0x0E, 0x10]);
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let port = QueuePort::default();
+        let created = now().0;
+        // 125 BPM at 24 TPQN, a tick every 20 ms
+        let mut sync = queue_sync(&port, 125.0, SyncMode::Clock, Heartbeat::Sensing);
+        let next = sync.next_heartbeat().unwrap() - created;
+        assert!(next >= Duration::from_millis(270) && next < Duration::from_millis(300));
+        // Scheduled messages are traffic as well
+        sync.schedule(created + Duration::from_millis(100), vec![0x90, 60, 100]);
+        sync.run();
+        let next = sync.next_heartbeat().unwrap() - created;
+        assert_eq!(next, Duration::from_millis(370));
+
+        // Idle ticks end a whole tick before the start
+        sync.update(
+            125.0,
+            None,
+            Rate::default(),
+            SyncMode::Clock,
+            Heartbeat::Clock,
+        )
+        .unwrap();
+        let start = created + Duration::from_millis(1000);
+        sync.start(Some(start), Duration::ZERO);
+        let next = sync.next_heartbeat().unwrap();
+        assert_eq!((start - next).as_micros() % 20_000, 0);
+        assert!(next > created + Duration::from_millis(100));
+        assert!(next <= created + Duration::from_millis(120));
+        sync.last_sent = start - Duration::from_millis(20);
+        assert!(sync.next_heartbeat().is_none());
+
+        sync.stop();
+        sync.update(
+            125.0,
+            None,
+            Rate::default(),
+            SyncMode::Clock,
+            Heartbeat::Off,
+        )
+        .unwrap();
+        assert!(sync.next_heartbeat().is_none());
+    }
+
+    #[test]
+    fn test_cancel_count_in() {
+        let port = QueuePort::default();
+        let mut sync = queue_sync(&port, 120.0, SyncMode::Clock, Heartbeat::Off);
+        let cue = Cue {
+            channel: 9,
+            note: 37,
+            velocity: 100,
+            scope: CueScope::Global,
+        };
+        let beat = Duration::from_millis(500);
+        let first = now().0 + Duration::from_millis(1200);
+        let second = first + Duration::from_millis(2000);
+        sync.count_in(first, beat, 2, &cue);
+        sync.count_in(second, beat, 2, &cue);
+        // The first click of the first count-in is queued already
+        sync.run();
+        assert_eq!(port.0.lock().unwrap().len(), 2);
+
+        sync.cancel_count_in(first);
+        assert!(port.0.lock().unwrap().is_empty());
+        let note_ons: Vec<Duration> = sync
+            .scheduled
+            .iter()
+            .filter(|s| !is_note_off(&s.msg))
+            .map(|s| s.time)
+            .collect();
+        assert_eq!(note_ons, vec![second - beat * 2, second - beat]);
+        // Note offs are harmless and stay
+        assert_eq!(sync.scheduled.len(), 6);
+    }
+
+    #[test]
+    fn test_cancel_scene() {
+        let port = QueuePort::default();
+        let mut sync = queue_sync(&port, 120.0, SyncMode::Clock, Heartbeat::Off);
+        let time = now().0 + Duration::from_millis(200);
+        sync.schedule_scene(time, vec![0xC0, 1]);
+        sync.schedule(time, vec![0xC1, 2]);
+        // Both are queued already
+        sync.run();
+        assert_eq!(port.0.lock().unwrap().len(), 2);
+
+        sync.cancel_scene();
+        assert!(port.0.lock().unwrap().is_empty());
+        let msgs: Vec<&[u8]> = sync.scheduled.iter().map(|s| s.msg.as_slice()).collect();
+        assert_eq!(msgs, vec![&[0xC1, 2][..]]);
+    }
+
+    #[test]
+    fn test_fail_drops_pending() {
+        let port = QueuePort::default();
+        let mut sync = queue_sync(&port, 120.0, SyncMode::Clock, Heartbeat::Sensing);
+        sync.start(Some(now().0 + Duration::from_secs(1)), Duration::ZERO);
+        sync.schedule(now().0 + Duration::from_secs(2), vec![0xC0, 1]);
+        assert!(sync.run().is_some());
+
+        // A failed port gives the clock thread nothing to wake up for
+        sync.fail("Port gone".to_owned());
+        assert!(sync.scheduled.is_empty() && sync.queued.is_empty());
+        assert_eq!(sync.run(), None);
+        assert_eq!(sync.start_time(), None);
+    }
+
+    #[test]
+    fn test_count_in_after_tempo_change() {
+        let port = QueuePort::default();
+        let mut sync = queue_sync(&port, 120.0, SyncMode::Clock, Heartbeat::Off);
+        let cue = Cue {
+            channel: 9,
+            note: 37,
+            velocity: 100,
+            scope: CueScope::Global,
+        };
+        let secs = |s: u64| Duration::from_secs(s);
+        let base = now().0 + secs(10);
+        // Half the tempo from base + 1s, a port joins at base + 3s on the old grid
+        sync.retime(base + secs(1), 60.0, None);
+        let start = base + secs(3);
+        sync.count_in(start, Duration::from_millis(500), 2, &cue);
+        sync.start(Some(start), Duration::ZERO);
+        // The second retime for the joining port keeps everything in place
+        sync.retime(base + secs(1), 60.0, None);
+
+        assert_eq!(sync.start_time(), Some(base + secs(5)));
+        let note_ons: Vec<Duration> = sync
+            .scheduled
+            .iter()
+            .filter(|s| !is_note_off(&s.msg))
+            .map(|s| s.time)
+            .collect();
+        assert_eq!(note_ons, vec![base + secs(3), base + secs(4)]);
+        // Clicks are cancelled by the start on the grid
+        sync.cancel_count_in(start);
+        assert!(sync.scheduled.iter().all(|s| is_note_off(&s.msg)));
+    }
 }
@@ -1,24 +1,58 @@
 use anyhow::{bail, Context, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender, TrySendError};
 use midir::{MidiOutput, MidiOutputPort};
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 use time::ext::NumericalStdDuration;
 
-use crate::midisync::{MidiSync, MidiSyncState};
+use crate::backend::{self, OutputOptions};
+use crate::midisync::{Cue, CueScope, Heartbeat, MidiSync, MidiSyncState, Rate, SyncMode};
+use crate::recording::Recording;
+use crate::rtpmidi::{RtpPeer, RtpSession};
+use crate::session::{Click, ProgramChange, Session};
+use crate::setlist::Setlist;
+use crate::tempomap::{TempoMap, TempoPoint};
+use crate::tickloop::{SyncHandle, TickCtrl, TickEvent};
+use crate::timing::{LatenessSummary, SyncMetrics};
 use tracing::{error, info, warn};
+use utils::midimessages::SystemCommonMessage;
 use utils::programclock::{now, ProgramTime};
 
 #[derive(Clone, Debug)]
 pub struct PortDisplay {
     pub info: PortInfo,
+    pub config: PortConfig,
     pub state: Option<MidiSyncState>,
+    pub start_time: Option<ProgramTime>,
+    pub recovery: Option<Recovery>,
+    pub metrics: Option<SyncMetrics>,
+    /// Number of times the port failed
+    pub errors: u64,
 }
 
 #[derive(Clone, Debug)]
 pub struct MultiSyncDisplay {
     pub state: MultiSyncState,
+    pub grid: BeatGrid,
     pub settings: Settings,
     pub ports: Vec<PortDisplay>,
+    pub scenes: Vec<String>,
+    pub groups: Vec<String>,
+    pub scene: Option<String>,
+    pub pending_scene: Option<(String, ProgramTime)>,
+    pub songs: Vec<String>,
+    pub song: Option<usize>,
+    pub pending_song: Option<(usize, ProgramTime)>,
+    /// Locate position in quarters, only set when playing a tempo map
+    pub position: Option<f64>,
+    pub tempo_map: Option<Arc<TempoMap>>,
+    pub pending_tempo: Option<(f64, ProgramTime)>,
+    pub recording: bool,
+    pub lateness: LatenessSummary,
+    pub command_errors: u64,
 }
 
 pub enum MultiSyncCommand {
@@ -27,9 +61,19 @@ pub enum MultiSyncCommand {
     AddListener(Sender<MultiSyncEvent>),
     AddSyncForPort(PortInfo),
     DelSyncForPort(PortInfo),
+    AddVirtualPort(String),
     UpdateSettings(Settings),
     StartPort(PortInfo),
     StopPort(PortInfo),
+    StartGroup(String),
+    StopGroup(String),
+    UpdatePortConfig(PortInfo, PortConfig),
+    RecallScene(String),
+    ArmSong(usize),
+    Locate(f64),
+    ResetPort(PortInfo),
+    SaveRecording,
+    Shutdown,
 }
 
 #[derive(Clone, Debug)]
@@ -38,7 +82,7 @@ pub enum MultiSyncEvent {
     Stopped,
     NewPorts(Vec<PortInfo>),
     SettingsUpdated(Settings),
-    DisplayUpdate(MultiSyncDisplay),
+    DisplayUpdate(Box<MultiSyncDisplay>),
 }
 
 pub struct MultiSyncCtrl {
@@ -52,19 +96,60 @@ pub enum MultiSyncState {
     Started(ProgramTime),
 }
 
+/// Anchor of the master beat grid, moved whenever the tempo changes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BeatGrid {
+    pub time: ProgramTime,
+    pub beat: f64,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeSignature {
+    pub num: u8,
+    pub den: u8,
+}
+
 #[derive(Clone, Debug)]
 pub struct Settings {
     pub bpm: f64,
     pub quantum: f64,
     pub tpqn: Option<f64>,
+    pub time_signature: TimeSignature,
+    pub min_bpm: f64,
+    pub max_bpm: f64,
+    /// Song Select sent to the ports before they start
+    pub song_select: Option<u8>,
 }
 
+pub const DEFAULT_MIN_BPM: f64 = 20.0;
+pub const DEFAULT_MAX_BPM: f64 = 400.0;
+const CONTROL_PERIOD: f64 = 2.0; // Interval of the control loop in ms
+
 pub struct MultiSync {
     ctrl: MultiSyncCtrl,
     port_enum: MidiOutput, // Client used to enumerate available ports
     clients: Vec<MultiSyncMidiClient>,
     settings: Settings,
+    initial_settings: Settings, // Songs fall back to these for what they leave out
     state: MultiSyncState,
+    grid: BeatGrid,
+    session: Session,
+    scene: Option<String>,
+    pending_scene: Option<(String, ProgramTime)>,
+    setlist: Setlist,
+    song: Option<usize>,
+    pending_song: Option<(usize, ProgramTime)>,
+    tempo_map: Option<Arc<TempoMap>>,
+    position: f64, // Position in quarters the tempo map is played from
+    pending_tempo: Option<(ProgramTime, TempoPoint)>,
+    recording: Option<Recording>,
+    ticks: TickCtrl,
+    output: OutputOptions,
+    rtp: Option<RtpSession>,
+    shutdown: bool,
+    lateness: LatenessSummary,
+    command_errors: u64,
     changed: bool,
     last_update: Option<ProgramTime>,
     last_port_update: Option<ProgramTime>,
@@ -72,19 +157,71 @@ pub struct MultiSync {
 
 #[derive(Clone, PartialEq)]
 pub struct PortInfo {
-    pub port: MidiOutputPort,
+    pub port: PortKind,
     pub name: String,
 }
 
+#[derive(Clone, PartialEq)]
+pub enum PortKind {
+    /// An existing output port of a device or another application
+    Device(MidiOutputPort),
+    /// A port midimaxe creates itself for software on the same machine to connect to
+    Virtual,
+    /// A peer of the RTP-MIDI session
+    Network(RtpPeer),
+}
+
 impl std::fmt::Debug for PortInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(format!("MIDI Output \"{}\"", self.name).as_ref())
+        match self.port {
+            PortKind::Device(_) => write!(f, "MIDI Output \"{}\"", self.name),
+            PortKind::Virtual => write!(f, "Virtual MIDI Output \"{}\"", self.name),
+            PortKind::Network(ref peer) => write!(f, "{:?}", peer),
+        }
     }
 }
 
+impl PortInfo {
+    pub fn is_virtual(&self) -> bool {
+        self.port == PortKind::Virtual
+    }
+}
+
+/// Per-port overrides of the global settings
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PortConfig {
+    pub tpqn: Option<f64>,
+    pub rate: Rate,
+    pub mode: SyncMode,
+    pub heartbeat: Heartbeat,
+    pub quantum: Option<f64>,
+    pub cue: Option<Cue>,
+    /// Note the cue plays, from the session file
+    pub click: Click,
+    pub song_select: Option<u8>,
+    /// Group from the session file the port belongs to
+    pub group: Option<String>,
+}
+
+/// Automatic reconnect of a port that failed, retried with exponential backoff
+#[derive(Clone, Copy, Debug)]
+pub struct Recovery {
+    pub attempts: u32,
+    pub next_attempt: ProgramTime,
+    rejoin: bool, // Start again at the next quantum once reconnected
+}
+
+const RETRY_MIN: f64 = 0.5; // Delay before the first reconnect in seconds
+const RETRY_MAX: f64 = 30.0;
+
 pub struct MultiSyncMidiClient {
     info: PortInfo,
-    sync: Option<MidiSync>,
+    config: PortConfig,
+    sync: Option<SyncHandle>,
+    recovery: Option<Recovery>,
+    metrics: Option<SyncMetrics>, // Reported by the tick loop once a second
+    errors: u64,
+    start_time: Option<ProgramTime>, // On the beat grid, a tempo change can move the actual start
 }
 
 impl MultiSyncCtrl {
@@ -116,8 +253,22 @@ impl MultiSyncCtrl {
 }
 
 impl MultiSync {
-    pub fn new() -> Result<(MultiSync, Sender<MultiSyncCommand>)> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        settings: Settings,
+        session: Session,
+        setlist: Setlist,
+        tempo_map: Option<TempoMap>,
+        recording: Option<Recording>,
+        ticks: TickCtrl,
+        output: OutputOptions,
+        rtp: Option<RtpSession>,
+    ) -> Result<(MultiSync, Sender<MultiSyncCommand>)> {
         let (ctrl, cmd) = MultiSyncCtrl::new();
+        let settings = match &tempo_map {
+            Some(map) => map.at(0.0).settings(&settings),
+            None => settings,
+        };
         let port_enum =
             MidiOutput::new("MultiSync Controller").context("Failed to create MidiOutput")?;
         Ok((
@@ -125,8 +276,26 @@ impl MultiSync {
                 ctrl,
                 port_enum,
                 clients: Vec::new(),
-                settings: Settings::new(130.0, 16.0, None),
+                initial_settings: settings.clone(),
+                settings,
                 state: MultiSyncState::Stopped,
+                grid: BeatGrid::new(now(), 0.0),
+                session,
+                scene: None,
+                pending_scene: None,
+                setlist,
+                song: None,
+                pending_song: None,
+                tempo_map: tempo_map.map(Arc::new),
+                position: 0.0,
+                pending_tempo: None,
+                recording,
+                ticks,
+                output,
+                rtp,
+                shutdown: false,
+                lateness: LatenessSummary::default(),
+                command_errors: 0,
                 changed: true,
                 last_update: None,
                 last_port_update: None,
@@ -135,15 +304,46 @@ impl MultiSync {
         ))
     }
 
-    pub fn run(&mut self) -> Result<Option<Duration>> {
-        let next_events: Vec<Option<Duration>> = self
-            .clients
-            .iter_mut()
-            .filter_map(|c| c.sync.as_mut())
-            .map(|s| s.run())
-            .collect();
+    /* Port discovery and commands are handled on a control thread, separate from
+     * the tick loop that only sends the clock. Slow operations like enumerating
+     * ports can not delay ticks this way. */
+    pub fn spawn(mut self) -> Result<JoinHandle<Result<()>>> {
+        thread::Builder::new()
+            .name("midimaxe control".to_owned())
+            .spawn(move || {
+                for name in self.session.virtual_ports.clone() {
+                    if let Err(e) = self.add_virtual_port(name) {
+                        error!(error = ?e, "Failed to create virtual port");
+                    }
+                }
+                while !self.shutdown {
+                    self.run();
+                    thread::sleep(CONTROL_PERIOD.std_milliseconds());
+                }
+                self.save_recording()
+            })
+            .context("Failed to start control thread")
+    }
 
+    fn run(&mut self) {
+        self.process_tick_events();
         self.process_cmds().unwrap_or(());
+        if let Some((scene, time)) = self.pending_scene.take() {
+            if time.0 <= now().0 {
+                self.record_marker(time, format!("Scene {}", scene));
+                self.scene = Some(scene);
+                self.changed = true;
+            } else {
+                self.pending_scene = Some((scene, time));
+            }
+        }
+        if let Some((song, time)) = self.pending_song {
+            if time.0 <= now().0 {
+                self.apply_song(song, time);
+            }
+        }
+        self.update_tempo_map();
+        self.recover_ports();
         if self
             .last_port_update
             .and_then(|t| Some(now().0 - t.0 > 1.0.std_seconds()))
@@ -160,23 +360,49 @@ impl MultiSync {
 
         if self.changed || timed_update {
             self.ctrl
-                .publish(MultiSyncEvent::DisplayUpdate(self.to_display()));
+                .publish(MultiSyncEvent::DisplayUpdate(Box::new(self.to_display())));
             self.last_update = Some(now());
             self.changed = false;
         }
+    }
 
-        let next_event: Option<Duration> = next_events.iter().fold(None, |a, b| match (a, b) {
-            (Some(a), Some(b)) => Some(b.min(&a).to_owned()),
-            (None, Some(b)) => Some(b.to_owned()),
-            (Some(a), None) => Some(a.to_owned()),
-            _ => None,
-        });
-
-        Ok(next_event)
+    fn process_tick_events(&mut self) {
+        while let Some(event) = self.ticks.get_event() {
+            match event {
+                TickEvent::State(id, seq, state, start_time) => {
+                    let client = self
+                        .clients
+                        .iter_mut()
+                        .find(|c| c.sync.as_ref().is_some_and(|s| s.id() == id));
+                    if let Some(client) = client {
+                        self.changed |= client.apply(seq, state, start_time);
+                    }
+                }
+                TickEvent::SyncMetrics(id, metrics) => {
+                    let client = self
+                        .clients
+                        .iter_mut()
+                        .find(|c| c.sync.as_ref().is_some_and(|s| s.id() == id));
+                    if let Some(client) = client {
+                        client.metrics = Some(metrics);
+                    }
+                }
+                TickEvent::Lateness(lateness) => self.lateness = lateness,
+                TickEvent::Removed(sync) => drop(sync),
+            }
+        }
     }
 
     fn update_ports(&mut self) -> Result<()> {
-        let ports = self.port_enum.ports();
+        let mut ports: Vec<PortKind> = self
+            .port_enum
+            .ports()
+            .into_iter()
+            .map(PortKind::Device)
+            .collect();
+        if let Some(rtp) = &self.rtp {
+            ports.extend(rtp.peers().into_iter().map(PortKind::Network));
+        }
         let existing_ports = self.clients.iter().map(|c| &c.info.port);
 
         let new_ports = ports
@@ -189,16 +415,22 @@ impl MultiSync {
 
         let new_port_info: Vec<PortInfo> = new_ports
             .flat_map(|port| -> Result<PortInfo> {
-                let name = self.port_enum.port_name(&port)?;
+                let name = match &port {
+                    PortKind::Device(device) => self.port_enum.port_name(device)?,
+                    PortKind::Network(peer) => format!("{} (RTP-MIDI {})", peer.name, peer.addr),
+                    PortKind::Virtual => bail!("BUG: Virtual ports are not enumerated"),
+                };
                 info!(port = name, "New Port");
                 Ok(PortInfo { port, name })
             })
             .collect();
 
         self.clients.retain(|p| {
-            if ports.contains(&p.info.port) {
+            // Virtual ports exist as long as their client
+            if p.info.is_virtual() || ports.contains(&p.info.port) {
                 true
             } else {
+                info!(port = ?p.info, "Port lost");
                 self.changed = true;
                 false
             }
@@ -206,14 +438,12 @@ impl MultiSync {
 
         let has_new_ports = !new_port_info.is_empty();
 
+        let session = &self.session;
         self.clients.extend(
             new_port_info
                 .clone()
                 .into_iter()
-                .map(|p| MultiSyncMidiClient {
-                    info: p,
-                    sync: None,
-                }),
+                .map(|info| MultiSyncMidiClient::new(info, session)),
         );
 
         if has_new_ports {
@@ -230,14 +460,27 @@ impl MultiSync {
             let result = match cmd {
                 MultiSyncCommand::AddSyncForPort(port) => self.add_sync_for_port(port),
                 MultiSyncCommand::DelSyncForPort(port) => self.del_sync_for_port(port),
+                MultiSyncCommand::AddVirtualPort(name) => self.add_virtual_port(name),
                 MultiSyncCommand::UpdateSettings(settings) => self.update_settings(settings),
                 MultiSyncCommand::Start => self.start(),
                 MultiSyncCommand::Stop => self.stop(),
                 MultiSyncCommand::StartPort(port) => self.start_port(port),
                 MultiSyncCommand::StopPort(port) => self.stop_port(port),
+                MultiSyncCommand::StartGroup(group) => self.start_group(group),
+                MultiSyncCommand::StopGroup(group) => self.stop_group(group),
+                MultiSyncCommand::UpdatePortConfig(port, config) => {
+                    self.update_port_config(port, config)
+                }
+                MultiSyncCommand::RecallScene(name) => self.recall_scene(name),
+                MultiSyncCommand::ArmSong(song) => self.arm_song(song),
+                MultiSyncCommand::Locate(beat) => self.locate(beat),
+                MultiSyncCommand::ResetPort(port) => self.reconnect(&port),
+                MultiSyncCommand::SaveRecording => self.save_recording(),
+                MultiSyncCommand::Shutdown => self.shutdown(),
                 _ => Ok(()),
             };
             if let Err(e) = result {
+                self.command_errors += 1;
                 error!(error = ?e, "Failed to run command");
             }
         }
@@ -248,7 +491,7 @@ impl MultiSync {
         let client = self
             .clients
             .iter_mut()
-            .find(|p| p.info.port == port.port)
+            .find(|p| p.info == port)
             .context("Port not found")?;
         if client.sync.is_some() {
             bail!(
@@ -256,29 +499,49 @@ impl MultiSync {
                 port
             );
         }
-        let midi_out = midir::MidiOutput::new("Midimaxe Sync Client")?
-            .connect(&client.info.port, "Midimaxe Sync Client Port");
-        if let Err(e) = midi_out {
-            bail!(
-                "AddSyncForPort: Failed to connect to MIDI output: {:?} : {}",
-                port,
-                e.to_string()
-            );
-        }
-        client.sync = Some(MidiSync::new(
-            midi_out.unwrap(),
-            self.settings.bpm,
-            self.settings.tpqn,
-        ));
+        client
+            .connect(&self.settings, self.output, &mut self.ticks)
+            .context("AddSyncForPort: Failed to connect to MIDI output")?;
         info!(port = ?port, "AddSyncForPort: Sync port added");
         Ok(())
     }
 
+    /* Virtual ports are created together with their sync, so applications can
+     * connect to them right away. Removing the sync removes the port. */
+    fn add_virtual_port(&mut self, name: String) -> Result<()> {
+        let name = name.trim().to_owned();
+        if name.is_empty() {
+            bail!("AddVirtualPort: Name is empty");
+        }
+        if self
+            .clients
+            .iter()
+            .any(|c| c.info.is_virtual() && c.info.name == name)
+        {
+            bail!("AddVirtualPort: Virtual port {:?} already exists", name);
+        }
+        let mut client = MultiSyncMidiClient::new(
+            PortInfo {
+                port: PortKind::Virtual,
+                name,
+            },
+            &self.session,
+        );
+        client
+            .connect(&self.settings, self.output, &mut self.ticks)
+            .context("AddVirtualPort: Failed to create virtual port")?;
+        info!(port = ?client.info, "AddVirtualPort: Virtual port created");
+        self.ctrl
+            .publish(MultiSyncEvent::NewPorts(vec![client.info.clone()]));
+        self.clients.push(client);
+        Ok(())
+    }
+
     fn del_sync_for_port(&mut self, port: PortInfo) -> Result<()> {
         let client = self
             .clients
             .iter_mut()
-            .find(|p| p.info.port == port.port)
+            .find(|p| p.info == port)
             .context("Port not found")?;
         {
             let sync = client.sync.as_ref().context(format!(
@@ -292,7 +555,12 @@ impl MultiSync {
         }
 
         drop(client.sync.take());
+        client.recovery = None;
         info!(port = ?port, "DelSyncForPort: Sync port removed");
+        if port.is_virtual() {
+            // Without its sync the port is gone
+            self.clients.retain(|c| c.info != port);
+        }
 
         Ok(())
     }
@@ -305,10 +573,11 @@ impl MultiSync {
             }
             info!(settings = ?settings, "New settings");
             self.settings = settings;
-            self.clients
-                .iter_mut()
-                .filter_map(|c| c.sync.as_mut())
-                .for_each(|s| s.update(self.settings.bpm, self.settings.tpqn).unwrap());
+            for client in self.clients.iter_mut() {
+                if let Err(e) = client.update_sync(&self.settings) {
+                    warn!(port = ?client.info, error = ?e, "Failed to update port settings");
+                }
+            }
             self.ctrl
                 .publish(MultiSyncEvent::SettingsUpdated(self.settings.clone()));
             Ok(())
@@ -321,26 +590,49 @@ impl MultiSync {
     }
 
     fn start(&mut self) -> Result<()> {
-        let start_time = match self.state {
+        match self.state {
             MultiSyncState::Stopped => {
+                if self.tempo_map.is_some() {
+                    self.locate(self.position)?;
+                }
                 let start_time = ProgramTime(now().0 + 0.1.std_seconds());
                 self.state = self.state.transition(MultiSyncState::Started(start_time));
-                start_time
+                self.grid = BeatGrid::new(start_time, self.position);
+                if let Some(recording) = self.recording.as_mut() {
+                    recording.start(start_time, &self.settings);
+                }
+                let position = self.tempo_map.as_ref().map(|_| self.position);
+                let timeline = self.timeline_offset();
+                for client in self.clients.iter_mut() {
+                    client.start(&self.settings, start_time, position, timeline);
+                }
             }
             MultiSyncState::Started(start_time) => {
-                let next_quantum = self.settings.next_quantum(start_time, None);
-                info!(
-                    ?start_time,
-                    ?next_quantum,
-                    "Starting all non-started clients"
-                );
-                next_quantum
+                info!(?start_time, "Starting all non-started clients");
+                let pending = self.pending_settings();
+                let offset = self.timeline_offset();
+                let mut starting = Vec::new();
+                for client in self.clients.iter_mut() {
+                    let next_quantum = self
+                        .settings
+                        .for_port(&client.config)
+                        .next_quantum(self.grid, None);
+                    let position = self
+                        .tempo_map
+                        .as_ref()
+                        .map(|_| self.settings.get_quarter(self.grid, Some(next_quantum)));
+                    let timeline = offset + next_quantum.0.saturating_sub(start_time.0);
+                    if client.start(&self.settings, next_quantum, position, timeline) {
+                        starting.push((client.info.clone(), next_quantum));
+                    }
+                    client.retime(&pending);
+                }
+                for (port, start_time) in starting {
+                    self.schedule_count_in(&port, start_time);
+                    self.record_marker(start_time, format!("Join {}", port.name));
+                }
             }
         };
-        self.clients
-            .iter_mut()
-            .filter_map(|c| c.sync.as_mut())
-            .for_each(|s| s.start(Some(start_time.0)));
 
         Ok(())
     }
@@ -352,52 +644,262 @@ impl MultiSync {
             .iter_mut()
             .filter_map(|c| c.sync.as_mut())
             .for_each(|s| s.stop());
+        if let Some(recording) = self.recording.as_mut() {
+            recording.stop(now());
+        }
         match self.state {
             MultiSyncState::Started(_) | MultiSyncState::Stopped => {
                 self.state = self.state.transition(MultiSyncState::Stopped)
             }
         };
+        // Stopping dropped the queued programs of a pending scene, they are sent right away
+        if let Some((scene, _)) = self.pending_scene.clone() {
+            self.recall_scene(scene)?;
+        }
+        // An armed song does not need to wait for the quantum anymore
+        if let Some((song, _)) = self.pending_song {
+            self.apply_song(song, now());
+            self.schedule_song_programs(song, now());
+        }
+        // Playback of a tempo map continues from the locate position on the next start
+        self.pending_tempo = None;
+        if self.tempo_map.is_some() {
+            self.locate(self.position)?;
+        }
         Ok(())
     }
 
     fn start_port(&mut self, port: PortInfo) -> Result<()> {
-        let start_time = match self.state {
-            MultiSyncState::Stopped => {
-                bail!(
-                    "Cannot start port \"{:?}\" while master is not running",
-                    port
-                );
+        let next_quantum = match self.clients.iter().find(|p| p.info == port) {
+            Some(client) => self
+                .settings
+                .for_port(&client.config)
+                .next_quantum(self.grid, None),
+            None => bail!("Port does not exist {:?}", port),
+        };
+        self.start_port_at(port, next_quantum)
+    }
+
+    /// Join the running master at next_quantum, which must be on the beat grid
+    fn start_port_at(&mut self, port: PortInfo, next_quantum: ProgramTime) -> Result<()> {
+        let MultiSyncState::Started(master_start) = self.state else {
+            bail!(
+                "Cannot start port \"{:?}\" while master is not running",
+                port
+            );
+        };
+        let pending = self.pending_settings();
+        let offset = self.timeline_offset();
+        let client = match self.clients.iter_mut().find(|p| p.info == port) {
+            Some(client) => client,
+            None => bail!("Port does not exist {:?}", port),
+        };
+        if client.sync.is_none() {
+            bail!("Port has no midisync attached: {:?}", port);
+        }
+        let position = self
+            .tempo_map
+            .as_ref()
+            .map(|_| self.settings.get_quarter(self.grid, Some(next_quantum)));
+        let timeline = offset + next_quantum.0.saturating_sub(master_start.0);
+        if !client.start(&self.settings, next_quantum, position, timeline) {
+            return Ok(());
+        }
+        info!(?port, grid = ?self.grid, ?next_quantum, ?position, "Starting port");
+        client.retime(&pending);
+        self.schedule_count_in(&port, next_quantum);
+        self.record_marker(next_quantum, format!("Join {}", port.name));
+
+        Ok(())
+    }
+
+    /// Time code at the start of the master, where the tempo map is played from
+    fn timeline_offset(&self) -> Duration {
+        let seconds = match &self.tempo_map {
+            Some(map) => map.seconds_at(self.position),
+            None => 0.0,
+        };
+        seconds.std_seconds()
+    }
+
+    fn schedule_count_in(&mut self, starting: &PortInfo, start_time: ProgramTime) {
+        for client in self.clients.iter_mut() {
+            let (Some(cue), Some(sync)) = (client.config.cue, client.sync.as_mut()) else {
+                continue;
+            };
+            let counts_in = match cue.scope {
+                CueScope::Port => client.info == *starting,
+                CueScope::Global => true,
+            };
+            if counts_in {
+                info!(port = ?client.info, ?starting, ?start_time, "Scheduling count-in");
+                let signature = self.settings.time_signature;
+                let beat = (60.0 / self.settings.bpm * signature.beat_length()).std_seconds();
+                sync.count_in(start_time.0, beat, signature.num as u32, &cue);
             }
-            MultiSyncState::Started(start_time) => {
-                let next_quantum = self.settings.next_quantum(start_time, None);
-                info!(?start_time, ?next_quantum, ?port, "Starting port");
-                next_quantum
+        }
+    }
+
+    // Clicks for a start no port is waiting for anymore
+    fn cancel_count_in(&mut self, start_time: Duration) {
+        let still_starting = self.clients.iter().any(|c| {
+            c.sync
+                .as_ref()
+                .is_some_and(|s| matches!(s.state(), MidiSyncState::Starting))
+                && c.start_time == Some(ProgramTime(start_time))
+        });
+        if still_starting {
+            return;
+        }
+        for client in self.clients.iter_mut() {
+            if let (Some(_), Some(sync)) = (client.config.cue, client.sync.as_mut()) {
+                sync.cancel_count_in(start_time);
             }
+        }
+    }
+
+    fn recover_ports(&mut self) {
+        let due: Vec<PortInfo> = self
+            .clients
+            .iter()
+            .filter(|c| c.recovery.is_some_and(|r| r.next_attempt.0 <= now().0))
+            .map(|c| c.info.clone())
+            .collect();
+        for port in due {
+            if let Err(e) = self.reconnect(&port) {
+                warn!(?port, error = ?e, "Failed to recover port");
+            }
+        }
+    }
+
+    /* Replace the connection of a port with a new one. Ports that were running
+     * rejoin at the next quantum, failed reconnects are retried with backoff. */
+    fn reconnect(&mut self, port: &PortInfo) -> Result<()> {
+        let client = self
+            .clients
+            .iter_mut()
+            .find(|p| p.info == *port)
+            .context("Port not found")?;
+        let Some(sync) = client.sync.as_mut() else {
+            bail!("Port has no midisync attached: {:?}", port);
         };
-        match self.clients.iter_mut().find(|p| p.info == port) {
-            Some(MultiSyncMidiClient {
-                info: _,
-                sync: Some(sync),
-            }) => {
-                info!(?port, ?start_time, "Starting port");
-                sync.start(Some(start_time.0));
+        let rejoin = match client.recovery {
+            Some(recovery) => recovery.rejoin,
+            None => matches!(
+                sync.state(),
+                MidiSyncState::Running | MidiSyncState::Starting
+            ),
+        };
+        let failed = matches!(sync.state(), MidiSyncState::Error(_));
+        sync.stop();
+        self.changed = true;
+        if let Err(e) = client.connect(&self.settings, self.output, &mut self.ticks) {
+            // The port stays failed until a reconnect succeeds, like one that failed while running
+            if !failed {
+                error!(?port, error = ?e, "Port failed");
+                client.errors += 1;
             }
-            Some(_) => bail!("Port has no midisync attached: {:?}", port),
+            if let Some(sync) = client.sync.as_mut() {
+                sync.fail(e.to_string());
+            }
+            let recovery = client.recovery.get_or_insert(Recovery::new(rejoin));
+            recovery.retry();
+            bail!(
+                "Reconnect attempt {} failed, retrying in {:.1}s: {:?}",
+                recovery.attempts,
+                (recovery.next_attempt.0 - now().0).as_secs_f64(),
+                e
+            );
+        }
+        client.recovery = None;
+        info!(?port, rejoin, "Port reconnected");
+        if rejoin && matches!(self.state, MultiSyncState::Started(_)) {
+            self.start_port(port.clone())?;
+        }
+        Ok(())
+    }
 
-            None => bail!("Port does not exist {:?}", port),
+    /* All stopped members of a group join at the same boundary, the first one that
+     * is a quantum boundary of every member, so each starts on its own quantum. */
+    fn start_group(&mut self, group: String) -> Result<()> {
+        if !matches!(self.state, MultiSyncState::Started(_)) {
+            bail!(
+                "StartGroup: Cannot start group {:?} while master is not running",
+                group
+            );
+        }
+        let members: Vec<(PortInfo, f64)> = self
+            .group_members(&group)?
+            .filter(|c| {
+                c.sync
+                    .as_ref()
+                    .is_some_and(|s| matches!(s.state(), MidiSyncState::Stopped))
+            })
+            .map(|c| (c.info.clone(), self.settings.for_port(&c.config).quantum))
+            .collect();
+        if members.is_empty() {
+            bail!("StartGroup: No stopped ports in group {:?}", group);
+        }
+        let settings = Settings {
+            quantum: common_quantum(members.iter().map(|(_, q)| *q)),
+            ..self.settings.clone()
+        };
+        let time = settings.next_quantum(self.grid, None);
+        info!(group, ?time, quantum = settings.quantum, "Starting group");
+        for (port, _) in members {
+            if let Err(e) = self.start_port_at(port, time) {
+                warn!(group, error = ?e, "StartGroup: Failed to start port");
+            }
         }
+        Ok(())
+    }
 
+    fn stop_group(&mut self, group: String) -> Result<()> {
+        let members: Vec<PortInfo> = self
+            .group_members(&group)?
+            .filter(|c| c.sync.is_some())
+            .map(|c| c.info.clone())
+            .collect();
+        info!(group, "Stopping group");
+        for port in members {
+            self.stop_port(port)?;
+        }
         Ok(())
     }
 
+    fn group_members<'a>(
+        &'a self,
+        group: &'a str,
+    ) -> Result<impl Iterator<Item = &'a MultiSyncMidiClient> + 'a> {
+        if !self.session.groups.iter().any(|g| g.name == group) {
+            bail!("Group does not exist {:?}", group);
+        }
+        Ok(self
+            .clients
+            .iter()
+            .filter(move |c| c.config.group.as_deref() == Some(group)))
+    }
+
     fn stop_port(&mut self, port: PortInfo) -> Result<()> {
         match self.clients.iter_mut().find(|p| p.info == port) {
             Some(MultiSyncMidiClient {
-                info: _,
                 sync: Some(sync),
+                start_time,
+                ..
             }) => {
                 info!(?port, "Stopping port");
+                let start_time = match sync.state() {
+                    MidiSyncState::Starting => start_time.map(|t| t.0),
+                    _ => None,
+                };
                 sync.stop();
+                if let Some(start_time) = start_time {
+                    self.cancel_count_in(start_time);
+                }
+                self.resend_pending_scene(&port);
+                if let MultiSyncState::Started(_) = self.state {
+                    self.record_marker(now(), format!("Stop {}", port.name));
+                }
             }
             Some(_) => bail!("Port has no midisync attached: {:?}", port),
 
@@ -407,11 +909,309 @@ impl MultiSync {
         Ok(())
     }
 
+    fn update_port_config(&mut self, port: PortInfo, config: PortConfig) -> Result<()> {
+        let client = self
+            .clients
+            .iter_mut()
+            .find(|p| p.info == port)
+            .context("Port not found")?;
+        if let Some(sync) = client.sync.as_ref() {
+            match sync.state() {
+                MidiSyncState::Stopped => (),
+                state => bail!(
+                    "UpdatePortConfig: Port {:?} must be stopped, is {:?}",
+                    port,
+                    state
+                ),
+            }
+        }
+        if config.quantum.is_some_and(|q| q < 1.0) {
+            bail!("UpdatePortConfig: Invalid quantum {:?}", config.quantum);
+        }
+        if config.song_select.is_some_and(|song| song > 127) {
+            bail!("UpdatePortConfig: Invalid song {:?}", config.song_select);
+        }
+        info!(?port, ?config, "Port config updated");
+        client.config = config;
+        client.update_sync(&self.settings)
+    }
+
+    /* Program changes are sent at the next quantum boundary, so all devices
+     * switch patches together. Without a running clock they are sent right away. */
+    fn recall_scene(&mut self, name: String) -> Result<()> {
+        let scene = self
+            .session
+            .scene(&name)
+            .context(format!("RecallScene: Scene does not exist: {}", name))?;
+        let time = match self.state {
+            MultiSyncState::Stopped => now(),
+            MultiSyncState::Started(_) => self.settings.next_quantum(self.grid, None),
+        };
+        info!(scene = name, ?time, "Recalling scene");
+        let programs = scene.programs.clone();
+        self.cancel_pending_scene();
+        self.schedule_programs(&programs, time, true);
+        self.pending_scene = Some((name, time));
+        Ok(())
+    }
+
+    // The scene recalled last wins, program changes of the one it replaces are dropped
+    fn cancel_pending_scene(&mut self) {
+        if let Some((scene, _)) = self.pending_scene.take() {
+            info!(scene, "Pending scene replaced");
+            for sync in self.clients.iter_mut().filter_map(|c| c.sync.as_mut()) {
+                sync.cancel_scene();
+            }
+        }
+    }
+
+    // Stopping a port drops its queued messages, it gets the programs of a pending scene now
+    fn resend_pending_scene(&mut self, port: &PortInfo) {
+        let Some(scene) = self
+            .pending_scene
+            .as_ref()
+            .and_then(|(name, _)| self.session.scene(name))
+        else {
+            return;
+        };
+        let Some(sync) = self
+            .clients
+            .iter_mut()
+            .find(|c| c.info == *port)
+            .and_then(|c| c.sync.as_mut())
+        else {
+            return;
+        };
+        for program in scene.programs.iter().filter(|p| p.matches(&port.name)) {
+            info!(?port, ?program, "Sending program change of pending scene");
+            for msg in program.to_midi() {
+                sync.schedule_scene(now().0, msg);
+            }
+        }
+    }
+
+    fn schedule_programs(&mut self, programs: &[ProgramChange], time: ProgramTime, scene: bool) {
+        for program in programs.iter() {
+            let clients = self
+                .clients
+                .iter_mut()
+                .filter(|c| program.matches(&c.info.name))
+                .filter_map(|c| c.sync.as_mut().map(|s| (&c.info, s)));
+            let mut matched = false;
+            for (port, sync) in clients {
+                info!(?port, ?program, "Scheduling program change");
+                for msg in program.to_midi() {
+                    match scene {
+                        true => sync.schedule_scene(time.0, msg),
+                        false => sync.schedule(time.0, msg),
+                    }
+                }
+                matched = true;
+            }
+            if !matched {
+                warn!(?program, "No connected port for program change");
+            }
+        }
+    }
+
+    /* A song armed while stopped is applied right away, while running all ports
+     * switch tempo at the next quantum boundary */
+    fn arm_song(&mut self, idx: usize) -> Result<()> {
+        let song = self
+            .setlist
+            .songs
+            .get(idx)
+            .cloned()
+            .context(format!("ArmSong: Song does not exist: {}", idx))?;
+        if self.pending_song.is_some() {
+            bail!("ArmSong: Another song is already armed");
+        }
+        if self.tempo_map.is_some() {
+            bail!("ArmSong: The tempo is driven by the tempo map");
+        }
+        let settings = song.settings(&self.settings, &self.initial_settings);
+        if !settings.is_valid() {
+            bail!(
+                "ArmSong: Invalid settings for song {}: {:?}",
+                song.name,
+                settings
+            );
+        }
+        let time = match self.state {
+            MultiSyncState::Stopped => {
+                info!(song = song.name, "Loading song");
+                self.update_settings(settings)?;
+                self.song = Some(idx);
+                now()
+            }
+            MultiSyncState::Started(_) => {
+                let time = self.settings.next_quantum(self.grid, None);
+                info!(song = song.name, ?time, "Arming song");
+                self.pending_song = Some((idx, time));
+                let pending = self.pending_settings();
+                self.clients.iter_mut().for_each(|c| c.retime(&pending));
+                time
+            }
+        };
+        self.schedule_song_programs(idx, time);
+        Ok(())
+    }
+
+    fn schedule_song_programs(&mut self, idx: usize, time: ProgramTime) {
+        let Some(song) = self.setlist.songs.get(idx) else {
+            return;
+        };
+        let scene = match &song.scene {
+            Some(scene) => match self.session.scene(scene) {
+                Some(scene) => Some((scene.name.clone(), scene.programs.clone())),
+                None => {
+                    warn!(scene, song = song.name, "Scene for song does not exist");
+                    None
+                }
+            },
+            None => None,
+        };
+        let programs = song.programs.clone();
+        if let Some((name, scene_programs)) = scene {
+            self.cancel_pending_scene();
+            self.schedule_programs(&scene_programs, time, true);
+            self.pending_scene = Some((name, time));
+        }
+        self.schedule_programs(&programs, time, false);
+    }
+
+    fn apply_song(&mut self, idx: usize, time: ProgramTime) {
+        let Some(song) = self.setlist.songs.get(idx) else {
+            return;
+        };
+        info!(song = song.name, ?time, "Song started");
+        self.settings = song.settings(&self.settings, &self.initial_settings);
+        self.grid = BeatGrid::new(time, 0.0);
+        self.song = Some(idx);
+        if let Some(recording) = self.recording.as_mut() {
+            recording.tempo(time, &self.settings);
+            recording.marker(time, format!("Song {}", song.name));
+        }
+        self.pending_song = None;
+        for client in self.clients.iter_mut() {
+            if let Some(MidiSyncState::Stopped) = client.sync.as_ref().map(|s| s.state()) {
+                client.update_sync(&self.settings).unwrap_or(());
+            }
+        }
+        self.ctrl
+            .publish(MultiSyncEvent::SettingsUpdated(self.settings.clone()));
+        self.changed = true;
+    }
+
+    /// Move the playback position of the tempo map, only possible while stopped
+    fn locate(&mut self, beat: f64) -> Result<()> {
+        let Some(map) = &self.tempo_map else {
+            bail!("Locate: No tempo map loaded");
+        };
+        if let MultiSyncState::Started(_) = self.state {
+            bail!("Locate: Cannot locate while running");
+        }
+        self.position = beat.max(0.0);
+        self.settings = map.at(self.position).settings(&self.settings);
+        info!(position = self.position, settings = ?self.settings, "Located in tempo map");
+        for client in self.clients.iter_mut() {
+            if let Err(e) = client.update_sync(&self.settings) {
+                warn!(port = ?client.info, error = ?e, "Failed to update port settings");
+            }
+        }
+        self.ctrl
+            .publish(MultiSyncEvent::SettingsUpdated(self.settings.clone()));
+        self.changed = true;
+        Ok(())
+    }
+
+    /* Tempo changes of the tempo map are handed to the ports as soon as the previous
+     * change took place, so they can be applied exactly on the beat grid */
+    fn update_tempo_map(&mut self) {
+        let (MultiSyncState::Started(_), Some(map)) = (&self.state, &self.tempo_map) else {
+            return;
+        };
+        let beat = match self.pending_tempo {
+            Some((time, _)) if time.0 > now().0 => return,
+            Some((time, point)) => {
+                info!(?time, ?point, "Tempo change");
+                self.settings = point.settings(&self.settings);
+                self.grid = BeatGrid::new(time, point.beat);
+                self.pending_tempo = None;
+                if let Some(recording) = self.recording.as_mut() {
+                    recording.tempo(time, &self.settings);
+                }
+                for client in self.clients.iter_mut() {
+                    if let Some(MidiSyncState::Stopped) = client.sync.as_ref().map(|s| s.state()) {
+                        client.update_sync(&self.settings).unwrap_or(());
+                    }
+                }
+                self.ctrl
+                    .publish(MultiSyncEvent::SettingsUpdated(self.settings.clone()));
+                self.changed = true;
+                point.beat
+            }
+            None => self.settings.get_quarter(self.grid, None),
+        };
+        if let Some(point) = map.next(beat) {
+            let time = self.settings.time_at(self.grid, point.beat);
+            self.pending_tempo = Some((time, point));
+            let pending = self.pending_settings();
+            self.clients.iter_mut().for_each(|c| c.retime(&pending));
+        }
+    }
+
+    fn record_marker(&mut self, time: ProgramTime, text: String) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.marker(time, text);
+        }
+    }
+
+    fn save_recording(&self) -> Result<()> {
+        match &self.recording {
+            Some(recording) => recording.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop all ports and end the control and tick loops, the recording is saved on exit
+    fn shutdown(&mut self) -> Result<()> {
+        info!("Shutting down");
+        self.shutdown = true;
+        let result = self.stop();
+        self.ticks.shutdown();
+        result
+    }
+
+    /// Settings that take effect at a later point in time
+    fn pending_settings(&self) -> Option<(ProgramTime, Settings)> {
+        if let Some((time, point)) = self.pending_tempo {
+            return Some((time, point.settings(&self.settings)));
+        }
+        let (idx, time) = self.pending_song?;
+        let song = self.setlist.songs.get(idx)?;
+        Some((time, song.settings(&self.settings, &self.initial_settings)))
+    }
+
     pub fn to_display(&self) -> MultiSyncDisplay {
         MultiSyncDisplay {
             state: self.state.clone(),
+            grid: self.grid,
             settings: self.settings.clone(),
             ports: self.clients.iter().map(|c| c.to_display()).collect(),
+            scenes: self.session.scenes.iter().map(|s| s.name.clone()).collect(),
+            groups: self.session.groups.iter().map(|g| g.name.clone()).collect(),
+            scene: self.scene.clone(),
+            pending_scene: self.pending_scene.clone(),
+            songs: self.setlist.songs.iter().map(|s| s.name.clone()).collect(),
+            song: self.song,
+            pending_song: self.pending_song,
+            position: self.tempo_map.as_ref().map(|_| self.position),
+            tempo_map: self.tempo_map.clone(),
+            pending_tempo: self.pending_tempo.map(|(time, point)| (point.bpm, time)),
+            recording: self.recording.is_some(),
+            lateness: self.lateness,
+            command_errors: self.command_errors,
         }
     }
 }
@@ -425,59 +1225,187 @@ impl MultiSyncState {
     }
 }
 
+/* Smallest quantum that all quanta divide, the first boundary they share.
+ * Quanta are compared in 1/32 quarters, the bar length of one 1/128 note. */
+fn common_quantum(quanta: impl Iterator<Item = f64>) -> f64 {
+    fn gcd(a: u64, b: u64) -> u64 {
+        match b {
+            0 => a,
+            _ => gcd(b, a % b),
+        }
+    }
+    let units = quanta
+        .map(|q| (q * 32.0).round().max(1.0) as u64)
+        .fold(1, |lcm, q| lcm / gcd(lcm, q) * q);
+    units as f64 / 32.0
+}
+
 impl Settings {
     pub fn new(bpm: f64, quantum: f64, tpqn: Option<f64>) -> Self {
-        Settings { bpm, quantum, tpqn }
+        Settings {
+            bpm,
+            quantum,
+            tpqn,
+            time_signature: TimeSignature::new(4, 4),
+            min_bpm: DEFAULT_MIN_BPM,
+            max_bpm: DEFAULT_MAX_BPM,
+            song_select: None,
+        }
     }
 
-    pub fn next_quantum(&self, start: ProgramTime, current: Option<ProgramTime>) -> ProgramTime {
-        let now = current.unwrap_or_else(|| now());
-        if now.0 <= start.0 {
-            return start;
+    pub fn with_song_select(self, song_select: Option<u8>) -> Self {
+        Settings {
+            song_select,
+            ..self
         }
+    }
 
-        let quantum_duration = (60.0 / self.bpm * self.quantum).std_seconds();
-        let runtime = now.0 - start.0;
-        let quantums = runtime.as_secs_f64() / quantum_duration.as_secs_f64();
-        let next_quantum = quantums.ceil();
-        ProgramTime(start.0 + (quantum_duration.as_secs_f64() * next_quantum).std_seconds())
+    pub fn with_bpm_limits(self, min_bpm: f64, max_bpm: f64) -> Self {
+        Settings {
+            min_bpm,
+            max_bpm,
+            ..self
+        }
     }
 
-    pub fn quantum(&self, start: ProgramTime, current: Option<ProgramTime>) -> f64 {
-        let now = current.unwrap_or_else(|| now());
-        if now.0 <= start.0 {
-            return 0.0;
+    /// Clamp a BPM value to the configured limits and round it to the
+    /// precision that can be entered in the UI (1/100 BPM)
+    pub fn clamp_bpm(&self, bpm: f64) -> f64 {
+        ((bpm * 100.0).round() / 100.0).clamp(self.min_bpm, self.max_bpm)
+    }
+
+    /// Settings as seen by a single port, with its overrides applied
+    pub fn for_port(&self, config: &PortConfig) -> Settings {
+        Settings {
+            tpqn: config.tpqn.or(self.tpqn),
+            quantum: config.quantum.unwrap_or(self.quantum),
+            song_select: config.song_select.or(self.song_select),
+            ..self.clone()
         }
-        let quantum_duration = (60.0 / self.bpm * self.quantum).std_seconds();
-        let runtime = now.0 - start.0;
-        let quantums = runtime.as_secs_f64() / quantum_duration.as_secs_f64();
-        quantums
     }
 
-    pub fn get_quarter(&self, start: ProgramTime, current: Option<ProgramTime>) -> f64 {
-        let current = current.unwrap_or_else(|| now());
-        if current.0 <= start.0 {
-            return 0.0;
+    pub fn next_quantum(&self, grid: BeatGrid, current: Option<ProgramTime>) -> ProgramTime {
+        let next_quantum = (self.get_quarter(grid, current) / self.quantum).ceil();
+        self.time_at(grid, next_quantum * self.quantum)
+    }
+
+    pub fn quantum(&self, grid: BeatGrid, current: Option<ProgramTime>) -> f64 {
+        self.get_quarter(grid, current) / self.quantum
+    }
+
+    /// Position on the beat grid in quarters, never before the grid anchor
+    pub fn get_quarter(&self, grid: BeatGrid, current: Option<ProgramTime>) -> f64 {
+        let current = current.unwrap_or_else(now);
+        if current.0 <= grid.time.0 {
+            return grid.beat;
         }
 
-        let quarter_duration = (60.0 / self.bpm) as f64;
-        let runtime = (current.0 - start.0).as_secs_f64();
-        let quarters = runtime / quarter_duration;
-        quarters
+        let quarter_duration = 60.0 / self.bpm;
+        let runtime = (current.0 - grid.time.0).as_secs_f64();
+        grid.beat + runtime / quarter_duration
+    }
+
+    pub fn time_at(&self, grid: BeatGrid, quarter: f64) -> ProgramTime {
+        let beats = (quarter - grid.beat).max(0.0);
+        ProgramTime(grid.time.0 + (60.0 / self.bpm * beats).std_seconds())
+    }
+
+    /// Number of beats (quarters) from current until time, 0.0 if time has passed
+    pub fn beats_until(&self, time: ProgramTime, current: Option<ProgramTime>) -> f64 {
+        let current = current.unwrap_or_else(now);
+        if time.0 <= current.0 {
+            return 0.0;
+        }
+        (time.0 - current.0).as_secs_f64() * self.bpm / 60.0
     }
 
     pub fn is_valid(&self) -> bool {
-        let mut valid = true;
+        self.validate().is_ok()
+    }
 
-        if self.bpm < 60.0 || self.bpm > 300.0 {
-            valid = false;
+    pub fn validate(&self) -> Result<()> {
+        if self.min_bpm.is_nan() || self.min_bpm <= 0.0 {
+            bail!("Minimum tempo must be above 0 BPM, is {}", self.min_bpm);
+        }
+        if self.max_bpm.is_nan() || self.max_bpm < self.min_bpm {
+            bail!(
+                "Maximum tempo {} BPM is below the minimum of {} BPM",
+                self.max_bpm,
+                self.min_bpm
+            );
         }
+        if !self.bpm.is_finite() {
+            bail!("Tempo must be a finite number, is {}", self.bpm);
+        }
+        if self.bpm < self.min_bpm || self.bpm > self.max_bpm {
+            bail!(
+                "Tempo {} BPM is outside of {}..{} BPM",
+                self.bpm,
+                self.min_bpm,
+                self.max_bpm
+            );
+        }
+        if self.quantum.is_nan() || self.quantum < 1.0 {
+            bail!("Quantum must be at least 1, is {}", self.quantum);
+        }
+        if self.time_signature.num == 0 || !self.time_signature.den.is_power_of_two() {
+            bail!("Invalid time signature {}", self.time_signature);
+        }
+        if let Some(song) = self.song_select.filter(|song| *song > 127) {
+            bail!("Song Select must be 0-127, is {}", song);
+        }
+        Ok(())
+    }
+}
+
+impl BeatGrid {
+    pub fn new(time: ProgramTime, beat: f64) -> Self {
+        BeatGrid { time, beat }
+    }
+}
+
+impl TimeSignature {
+    pub fn new(num: u8, den: u8) -> Self {
+        TimeSignature { num, den }
+    }
+
+    /// Length of a beat in quarters
+    pub fn beat_length(&self) -> f64 {
+        4.0 / self.den as f64
+    }
+
+    /// Length of a bar in quarters
+    pub fn bar_length(&self) -> f64 {
+        self.num as f64 * self.beat_length()
+    }
+}
 
-        if self.quantum < 1.0 {
-            valid = false;
+impl std::fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+impl FromStr for TimeSignature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (num, den) = s
+            .split_once('/')
+            .context(format!("Time signature must look like 4/4: {}", s))?;
+        let signature = TimeSignature::new(num.trim().parse()?, den.trim().parse()?);
+        if signature.num == 0 || !signature.den.is_power_of_two() {
+            bail!("Invalid time signature: {}", s);
         }
+        Ok(signature)
+    }
+}
+
+impl TryFrom<String> for TimeSignature {
+    type Error = anyhow::Error;
 
-        valid
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
     }
 }
 
@@ -491,11 +1419,145 @@ impl Drop for MultiSync {
 }
 
 impl MultiSyncMidiClient {
+    fn new(info: PortInfo, session: &Session) -> MultiSyncMidiClient {
+        let config = PortConfig {
+            click: session.port_click(&info.name),
+            song_select: session.port_song(&info.name),
+            group: session.port_group(&info.name),
+            ..Default::default()
+        };
+        MultiSyncMidiClient {
+            info,
+            config,
+            sync: None,
+            recovery: None,
+            metrics: None,
+            errors: 0,
+            start_time: None,
+        }
+    }
+
     pub fn to_display(&self) -> PortDisplay {
         PortDisplay {
             info: self.info.clone(),
-            state: self.sync.as_ref().and_then(|s| Some(s.state())),
+            config: self.config.clone(),
+            state: self.sync.as_ref().map(|s| s.state()),
+            start_time: self
+                .sync
+                .as_ref()
+                .and_then(|s| s.start_time())
+                .map(ProgramTime),
+            recovery: self.recovery,
+            metrics: self.metrics,
+            errors: self.errors,
+        }
+    }
+
+    fn connect(
+        &mut self,
+        settings: &Settings,
+        output: OutputOptions,
+        ticks: &mut TickCtrl,
+    ) -> Result<()> {
+        let midi_out = backend::connect(output.backend, &self.info, "Midimaxe Sync Client")?;
+        let settings = settings.for_port(&self.config);
+        self.metrics = None;
+        self.sync = Some(ticks.add(MidiSync::new(
+            midi_out,
+            output.lookahead,
+            settings.bpm,
+            settings.tpqn,
+            self.config.rate,
+            self.config.mode,
+            self.config.heartbeat,
+        )));
+        Ok(())
+    }
+
+    /// Apply a state reported by the tick loop, returns true if the state changed
+    fn apply(&mut self, seq: u64, state: MidiSyncState, start_time: Option<Duration>) -> bool {
+        let Some(sync) = self.sync.as_mut() else {
+            return false;
+        };
+        if let Some(before) = sync.apply(seq, state.clone(), start_time) {
+            match &state {
+                MidiSyncState::Error(e) => {
+                    error!(port = ?self.info, error = e, "Port failed");
+                    self.errors += 1;
+                    let rejoin = matches!(before, MidiSyncState::Running | MidiSyncState::Starting);
+                    self.recovery = Some(Recovery::new(rejoin));
+                }
+                state => info!(port = ?self.info, from = ?before, to = ?state, "Port state change"),
+            }
+            return true;
         }
+        false
+    }
+
+    /// Start at time, continuing from position (in quarters) if given and with the time
+    /// code at timeline. Returns false if the port was not stopped.
+    fn start(
+        &mut self,
+        settings: &Settings,
+        time: ProgramTime,
+        position: Option<f64>,
+        timeline: Duration,
+    ) -> bool {
+        let Some(sync) = self.sync.as_mut() else {
+            return false;
+        };
+        if !matches!(sync.state(), MidiSyncState::Stopped) {
+            return false;
+        }
+        // The song has to be selected before the song position and the start
+        if let Some(song) = settings.for_port(&self.config).song_select {
+            sync.schedule(now().0, SystemCommonMessage::SongSelect(song).to_midi());
+        }
+        sync.start(Some(time.0), timeline);
+        self.start_time = Some(time);
+        // Song position is counted in sixteenths of the port's own tempo
+        let sixteenths = position.unwrap_or(0.0) * self.config.rate.factor() * 4.0;
+        if sixteenths.round() > 0.0 {
+            sync.locate(sixteenths.round().min(0x3FFF as f64) as u16);
+        }
+        true
+    }
+
+    fn retime(&mut self, pending: &Option<(ProgramTime, Settings)>) {
+        if let (Some(sync), Some((time, settings))) = (self.sync.as_mut(), pending) {
+            let settings = settings.for_port(&self.config);
+            sync.retime(time.0, settings.bpm, settings.tpqn);
+        }
+    }
+
+    fn update_sync(&mut self, settings: &Settings) -> Result<()> {
+        let settings = settings.for_port(&self.config);
+        match self.sync.as_mut() {
+            Some(sync) => sync.update(
+                settings.bpm,
+                settings.tpqn,
+                self.config.rate,
+                self.config.mode,
+                self.config.heartbeat,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Recovery {
+    fn new(rejoin: bool) -> Self {
+        Recovery {
+            attempts: 0,
+            next_attempt: ProgramTime(now().0 + RETRY_MIN.std_seconds()),
+            rejoin,
+        }
+    }
+
+    fn retry(&mut self) {
+        self.attempts += 1;
+        let delay = (RETRY_MIN * 2f64.powi(self.attempts as i32)).min(RETRY_MAX);
+        self.next_attempt = ProgramTime(now().0 + delay.std_seconds());
     }
 }
 
@@ -503,12 +1565,111 @@ impl Default for MultiSyncDisplay {
     fn default() -> Self {
         Self {
             state: MultiSyncState::Stopped,
-            settings: Settings {
-                bpm: 130.,
-                quantum: 4.,
-                tpqn: None,
-            },
+            grid: BeatGrid::new(ProgramTime(Duration::ZERO), 0.0),
+            settings: Settings::new(130., 4., None),
             ports: vec![],
+            scenes: vec![],
+            groups: vec![],
+            scene: None,
+            pending_scene: None,
+            songs: vec![],
+            song: None,
+            pending_song: None,
+            position: None,
+            tempo_map: None,
+            pending_tempo: None,
+            recording: false,
+            lateness: LatenessSummary::default(),
+            command_errors: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: f64) -> ProgramTime {
+        ProgramTime(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn test_next_quantum() {
+        // 120 BPM, 2 quarters per second, quantum every 2 seconds
+        let settings = Settings::new(120.0, 4.0, None);
+        let grid = BeatGrid::new(t(10.0), 0.0);
+        assert_eq!(settings.next_quantum(grid, Some(t(5.0))), t(10.0));
+        assert_eq!(settings.next_quantum(grid, Some(t(10.0))), t(10.0));
+        assert_eq!(settings.next_quantum(grid, Some(t(10.5))), t(12.0));
+        assert_eq!(settings.next_quantum(grid, Some(t(13.0))), t(14.0));
+
+        let port = PortConfig {
+            quantum: Some(16.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            settings.for_port(&port).next_quantum(grid, Some(t(10.5))),
+            t(18.0)
+        );
+
+        // Song Select of the port wins over the one for all ports
+        let settings = settings.with_song_select(Some(3));
+        assert_eq!(settings.for_port(&port).song_select, Some(3));
+        let port = PortConfig {
+            song_select: Some(7),
+            ..port
+        };
+        assert_eq!(settings.for_port(&port).song_select, Some(7));
+    }
+
+    #[test]
+    fn test_common_quantum() {
+        assert_eq!(common_quantum([16.0, 4.0].into_iter()), 16.0);
+        assert_eq!(common_quantum([16.0, 12.0].into_iter()), 48.0);
+        // Four bars of 7/8 and four bars of 4/4
+        assert_eq!(common_quantum([14.0, 16.0].into_iter()), 112.0);
+        assert_eq!(common_quantum([3.5, 1.0].into_iter()), 7.0);
+
+        // A group with quanta of 12 and 16 starts at beat 48, not at the later of 36 and 32
+        let settings = Settings {
+            quantum: common_quantum([12.0, 16.0].into_iter()),
+            ..Settings::new(60.0, 4.0, None)
+        };
+        let grid = BeatGrid::new(t(0.0), 0.0);
+        assert_eq!(settings.next_quantum(grid, Some(t(30.0))), t(48.0));
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        assert!(Settings::new(120.0, 16.0, None).validate().is_ok());
+        let bpm = |bpm, min, max| Settings::new(bpm, 16.0, None).with_bpm_limits(min, max);
+        assert!(bpm(0.0, 0.0, 400.0).validate().is_err());
+        assert!(bpm(f64::NAN, 20.0, 400.0).validate().is_err());
+        assert!(bpm(120.0, f64::NAN, 400.0).validate().is_err());
+        assert!(bpm(120.0, 200.0, 100.0).validate().is_err());
+        assert!(bpm(500.0, 20.0, 400.0).validate().is_err());
+        assert!(bpm(f64::INFINITY, 20.0, f64::INFINITY).validate().is_err());
+        assert!(bpm(1000.0, 20.0, f64::INFINITY).validate().is_ok());
+    }
+
+    #[test]
+    fn test_grid_offset() {
+        // Grid moved to beat 6 at t=10, next quantum of 4 is at beat 8
+        let settings = Settings::new(60.0, 4.0, None);
+        let grid = BeatGrid::new(t(10.0), 6.0);
+        assert_eq!(settings.get_quarter(grid, Some(t(11.0))), 7.0);
+        assert_eq!(settings.next_quantum(grid, Some(t(10.5))), t(12.0));
+        assert_eq!(settings.time_at(grid, 12.0), t(16.0));
+    }
+
+    #[test]
+    fn test_time_signature() {
+        let sig: TimeSignature = "7/8".parse().unwrap();
+        assert_eq!(sig, TimeSignature::new(7, 8));
+        assert_eq!(sig.bar_length(), 3.5);
+        assert_eq!(sig.to_string(), "7/8");
+        assert!("4".parse::<TimeSignature>().is_err());
+        assert!("4/3".parse::<TimeSignature>().is_err());
+        assert!("0/4".parse::<TimeSignature>().is_err());
+    }
+}
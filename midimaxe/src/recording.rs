@@ -0,0 +1,237 @@
+use anyhow::{Context, Result};
+use midly::num::{u15, u24, u28};
+use midly::{Format, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::path::{Path, PathBuf};
+use tracing::info;
+use utils::programclock::ProgramTime;
+
+use crate::multisync::{Settings, TimeSignature};
+
+const PPQ: u16 = 960;
+const MIN_BPM: f64 = 60_000_000.0 / 0xFF_FFFF as f64; // Slowest tempo a tempo event holds
+
+#[derive(Debug, Clone, PartialEq)]
+enum RecordedEvent {
+    Tempo(f64),
+    TimeSignature(TimeSignature),
+    Marker(String),
+}
+
+/// Segment of the recording timeline with a constant tempo
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    time: ProgramTime,
+    beat: f64,
+    bpm: f64,
+}
+
+/* Records the transport of a session (tempo changes, starts, stops and markers)
+ * as a Standard MIDI File to line up recordings with the clock afterwards.
+ * The recording timeline starts with the first start and keeps running while
+ * stopped, so it matches a recorder that runs through the whole session.
+ * Every start is moved to a bar line of the recording by adjusting the tempo
+ * of the pause before it, so the beat grid of each run lines up with the file. */
+pub struct Recording {
+    path: PathBuf,
+    anchor: Option<Anchor>,
+    time_signature: Option<TimeSignature>,
+    stopped: Option<(ProgramTime, f64)>,
+    events: Vec<(f64, RecordedEvent)>,
+}
+
+impl Recording {
+    pub fn new(path: &Path) -> Recording {
+        Recording {
+            path: path.to_owned(),
+            anchor: None,
+            time_signature: None,
+            stopped: None,
+            events: Vec::new(),
+        }
+    }
+
+    /// Start of the master clock at time
+    pub fn start(&mut self, time: ProgramTime, settings: &Settings) {
+        let bar = settings.time_signature.bar_length();
+        let beat = match (self.anchor, self.stopped.take()) {
+            (Some(anchor), Some((stop_time, stop_beat))) => {
+                let mut beat = (anchor.beat_at(time) / bar).round() * bar;
+                let pause = (time.0.as_secs_f64() - stop_time.0.as_secs_f64()).max(0.001);
+                // A pause of a tiny fraction of a bar would need a tempo too slow to record
+                while (beat - stop_beat) * 60.0 / pause < MIN_BPM {
+                    beat += bar;
+                }
+                let bpm = (beat - stop_beat) * 60.0 / pause;
+                self.events.push((stop_beat, RecordedEvent::Tempo(bpm)));
+                beat
+            }
+            (Some(anchor), None) => anchor.beat_at(time),
+            (None, _) => 0.0,
+        };
+        self.anchor = Some(Anchor {
+            time,
+            beat,
+            bpm: settings.bpm,
+        });
+        self.events.push((beat, RecordedEvent::Tempo(settings.bpm)));
+        self.record_time_signature(beat, settings.time_signature);
+        self.events
+            .push((beat, RecordedEvent::Marker("Start".to_owned())));
+    }
+
+    pub fn stop(&mut self, time: ProgramTime) {
+        let Some(beat) = self.beat_at(time) else {
+            return;
+        };
+        if self.stopped.is_none() {
+            self.stopped = Some((time, beat));
+            self.events
+                .push((beat, RecordedEvent::Marker("Stop".to_owned())));
+        }
+    }
+
+    /// Tempo or time signature change at time, the tempo of a pause is set by the next start
+    pub fn tempo(&mut self, time: ProgramTime, settings: &Settings) {
+        let (Some(beat), None) = (self.beat_at(time), self.stopped) else {
+            return;
+        };
+        self.anchor = Some(Anchor {
+            time,
+            beat,
+            bpm: settings.bpm,
+        });
+        self.events.push((beat, RecordedEvent::Tempo(settings.bpm)));
+        self.record_time_signature(beat, settings.time_signature);
+    }
+
+    pub fn marker(&mut self, time: ProgramTime, text: String) {
+        if let Some(beat) = self.beat_at(time) {
+            self.events.push((beat, RecordedEvent::Marker(text)));
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let events = self.sorted_events();
+        let mut track = Vec::new();
+        let mut last_tick = 0;
+        for (beat, event) in events.iter() {
+            let tick = (beat.max(0.0) * PPQ as f64).round() as u32;
+            let kind = match event {
+                RecordedEvent::Tempo(bpm) => {
+                    let us_per_quarter = (60_000_000.0 / bpm).round() as u32;
+                    MetaMessage::Tempo(
+                        u24::try_from(us_per_quarter)
+                            .with_context(|| format!("Tempo of {} BPM cannot be recorded", bpm))?,
+                    )
+                }
+                RecordedEvent::TimeSignature(sig) => {
+                    MetaMessage::TimeSignature(sig.num, sig.den.trailing_zeros() as u8, 24, 8)
+                }
+                RecordedEvent::Marker(text) => MetaMessage::Marker(text.as_bytes()),
+            };
+            track.push(TrackEvent {
+                delta: u28::new(tick - last_tick),
+                kind: TrackEventKind::Meta(kind),
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
+        let mut smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(PPQ)),
+        ));
+        smf.tracks.push(track);
+        smf.save(&self.path)
+            .with_context(|| format!("Failed to write recording {:?}", self.path))?;
+        info!(path = ?self.path, events = self.events.len(), "Recording saved");
+        Ok(())
+    }
+
+    fn beat_at(&self, time: ProgramTime) -> Option<f64> {
+        self.anchor.map(|a| a.beat_at(time))
+    }
+
+    fn record_time_signature(&mut self, beat: f64, signature: TimeSignature) {
+        if self.time_signature != Some(signature) {
+            self.time_signature = Some(signature);
+            self.events
+                .push((beat, RecordedEvent::TimeSignature(signature)));
+        }
+    }
+
+    // Events are recorded in the order they are scheduled, not in the order they happen
+    fn sorted_events(&self) -> Vec<(f64, RecordedEvent)> {
+        let mut events = self.events.clone();
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        events
+    }
+}
+
+impl Anchor {
+    fn beat_at(&self, time: ProgramTime) -> f64 {
+        let elapsed = time.0.as_secs_f64() - self.time.0.as_secs_f64();
+        self.beat + elapsed * self.bpm / 60.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn t(secs: f64) -> ProgramTime {
+        ProgramTime(Duration::from_secs_f64(secs))
+    }
+
+    #[test]
+    fn test_restart_on_bar() {
+        let mut rec = Recording::new(Path::new("unused.mid"));
+        let settings = Settings::new(120.0, 16.0, None);
+        rec.start(t(10.0), &settings);
+        rec.marker(t(12.0), "Join".to_owned());
+        // Stop after 9 quarters and restart 1.9 seconds later, close to bar 3
+        rec.stop(t(14.5));
+        rec.start(t(16.4), &settings);
+
+        let events = rec.sorted_events();
+        assert!(events.contains(&(4.0, RecordedEvent::Marker("Join".to_owned()))));
+        assert!(events.contains(&(9.0, RecordedEvent::Marker("Stop".to_owned()))));
+        assert!(events.contains(&(12.0, RecordedEvent::Marker("Start".to_owned()))));
+        // The pause is stretched to 3 quarters in 1.9 seconds
+        let pause = events
+            .iter()
+            .find_map(|(beat, e)| match e {
+                RecordedEvent::Tempo(bpm) if *beat == 9.0 => Some(*bpm),
+                _ => None,
+            })
+            .unwrap();
+        assert!((pause - 3.0 * 60.0 / 1.9).abs() < 1e-6);
+        assert!((rec.beat_at(t(17.4)).unwrap() - 14.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_restart_right_after_stop() {
+        let mut rec = Recording::new(Path::new("unused.mid"));
+        let settings = Settings::new(120.0, 16.0, None);
+        rec.start(t(10.0), &settings);
+        // Stop just before bar 3 and restart right after it
+        rec.stop(t(13.995));
+        rec.start(t(14.2), &settings);
+
+        let events = rec.sorted_events();
+        // Bar 3 is only 0.01 quarters away, the pause takes up to bar 4 instead
+        assert!(events.contains(&(12.0, RecordedEvent::Marker("Start".to_owned()))));
+        let pause = events
+            .iter()
+            .find_map(|(beat, e)| match e {
+                RecordedEvent::Tempo(bpm) if (beat - 7.99).abs() < 1e-6 => Some(*bpm),
+                _ => None,
+            })
+            .unwrap();
+        assert!(pause >= MIN_BPM);
+    }
+}
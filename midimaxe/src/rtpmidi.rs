@@ -0,0 +1,926 @@
+use anyhow::{bail, Context, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use time::ext::NumericalStdDuration;
+use tracing::{info, warn};
+use utils::programclock::now;
+
+use crate::backend::MidiOut;
+
+const PROTOCOL_VERSION: u32 = 2;
+const PAYLOAD_TYPE: u8 = 0x61; // Dynamic RTP payload type used by AppleMIDI
+const POLL: f64 = 5.0; // Longest wait in ms for a packet on the data port
+const INVITE_RETRY: f64 = 2.0; // Seconds between invitations of peers that did not answer
+const SYNC_INTERVAL: f64 = 5.0; // Seconds between clock synchronizations we initiate
+const SESSION_TIMEOUT: f64 = 60.0; // Seconds without a packet after which a peer is dropped
+const NO_ACK: u32 = u32::MAX;
+
+/* An AppleMIDI session, the session protocol that RTP-MIDI (RFC 6295) is used
+ * with by macOS, iOS and rtpMIDI on Windows. Every session has a control port
+ * and the data port above it. Peers invite us or get invited to the addresses
+ * given on the command line. Peers that joined are listed as ports. The session
+ * thread only handles the session protocol, MIDI is sent by RtpOut. */
+pub struct RtpSession {
+    port: u16,
+    peers: Arc<Mutex<Vec<RtpPeer>>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A peer that joined the session, the data socket is shared by all peers
+#[derive(Clone)]
+pub struct RtpPeer {
+    pub name: String,
+    pub addr: SocketAddr, // Data port of the peer
+    ssrc: u32,
+    local_ssrc: u32,
+    socket: Arc<UdpSocket>,
+    acked: Arc<AtomicU32>, // Last sequence number the peer reported received, NO_ACK if none
+}
+
+impl PartialEq for RtpPeer {
+    fn eq(&self, other: &Self) -> bool {
+        self.ssrc == other.ssrc && self.addr == other.addr
+    }
+}
+
+impl std::fmt::Debug for RtpPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RTP-MIDI peer \"{}\" {}", self.name, self.addr)
+    }
+}
+
+impl RtpSession {
+    /// Listen on port (control) and port + 1 (data), port 0 picks free ports
+    pub fn spawn(name: &str, port: u16, invite: Vec<SocketAddr>) -> Result<RtpSession> {
+        let (control, data) = bind(port)?;
+        let port = control.local_addr()?.port();
+        control.set_nonblocking(true)?;
+        data.set_read_timeout(Some(POLL.std_milliseconds()))?;
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut session = Session {
+            name: name.to_owned(),
+            ssrc: random(),
+            control,
+            data: Arc::new(data),
+            targets: invite,
+            invites: Vec::new(),
+            participants: Vec::new(),
+            peers: peers.clone(),
+        };
+        let stop = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name("midimaxe rtp-midi".to_owned())
+            .spawn(move || session.run(&stop))
+            .context("Failed to start RTP-MIDI session thread")?;
+        Ok(RtpSession {
+            port,
+            peers,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn peers(&self) -> Vec<RtpPeer> {
+        self.peers.lock().map(|p| p.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for RtpSession {
+    // Peers are told we leave before the thread ends
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn bind(port: u16) -> Result<(UdpSocket, UdpSocket)> {
+    let attempts = if port == 0 { 20 } else { 1 };
+    for _ in 0..attempts {
+        let control = UdpSocket::bind(("0.0.0.0", port))
+            .with_context(|| format!("Failed to bind RTP-MIDI control port {}", port))?;
+        let data_port = control.local_addr()?.port().wrapping_add(1);
+        match UdpSocket::bind(("0.0.0.0", data_port)) {
+            Ok(data) => return Ok((control, data)),
+            Err(e) if port != 0 => {
+                bail!("Failed to bind RTP-MIDI data port {}: {}", data_port, e)
+            }
+            Err(_) => (),
+        }
+    }
+    bail!("Failed to find free RTP-MIDI control and data ports")
+}
+
+fn random() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+// Session and RTP timestamps count 100µs since the program started
+fn timestamp(time: Duration) -> u64 {
+    (time.as_micros() / 100) as u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Channel {
+    Control,
+    Data,
+}
+
+/// An invitation we sent that was not accepted on both ports yet
+struct Invite {
+    addr: SocketAddr, // Control port of the peer
+    token: u32,
+    channel: Channel,
+    next: Duration,
+    warned: bool,
+}
+
+struct Participant {
+    name: String,
+    ssrc: u32,
+    control: SocketAddr,
+    data: Option<SocketAddr>, // Set once the data port joined
+    initiated: bool,
+    last_seen: Duration,
+    next_sync: Duration,
+    acked: Arc<AtomicU32>,
+}
+
+struct Session {
+    name: String,
+    ssrc: u32,
+    control: UdpSocket,
+    data: Arc<UdpSocket>,
+    targets: Vec<SocketAddr>, // Control ports of the peers to invite
+    invites: Vec<Invite>,
+    participants: Vec<Participant>,
+    peers: Arc<Mutex<Vec<RtpPeer>>>,
+}
+
+impl Session {
+    fn run(&mut self, shutdown: &AtomicBool) {
+        let mut buf = [0u8; 1500];
+        while !shutdown.load(Ordering::Relaxed) {
+            loop {
+                match self.control.recv_from(&mut buf) {
+                    Ok((len, from)) => self.receive(Channel::Control, &buf[..len], from),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // Reported for invitations to peers that are not running
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => (),
+                    Err(e) => {
+                        warn!(error = ?e, "RTP-MIDI control port failed");
+                        break;
+                    }
+                }
+            }
+            match self.data.recv_from(&mut buf) {
+                Ok((len, from)) => self.receive(Channel::Data, &buf[..len], from),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+                    ) => {}
+                Err(e) => warn!(error = ?e, "RTP-MIDI data port failed"),
+            }
+            self.invite();
+            self.synchronize();
+            self.expire();
+        }
+        for p in self.participants.drain(..) {
+            let end = Exchange::End {
+                token: 0,
+                ssrc: self.ssrc,
+            };
+            let _ = self.control.send_to(&end.encode(), p.control);
+        }
+    }
+
+    // MIDI the peers send us is ignored, midimaxe is the master
+    fn receive(&mut self, channel: Channel, packet: &[u8], from: SocketAddr) {
+        let Some(exchange) = Exchange::decode(packet) else {
+            return;
+        };
+        let time = now().0;
+        if let Some(p) = self
+            .participants
+            .iter_mut()
+            .find(|p| p.ssrc == exchange.ssrc())
+        {
+            p.last_seen = time;
+        }
+        match exchange {
+            Exchange::Invitation { token, ssrc, name } => {
+                self.invited(channel, from, token, ssrc, name)
+            }
+            Exchange::Accept { token, ssrc, name } => {
+                self.accepted(channel, from, token, ssrc, name)
+            }
+            Exchange::Reject { token, .. } => {
+                if let Some(invite) = self.invites.iter_mut().find(|i| i.token == token) {
+                    warn!(peer = %invite.addr, "RTP-MIDI invitation rejected");
+                    invite.channel = Channel::Control;
+                    invite.next = time + INVITE_RETRY.std_seconds();
+                }
+            }
+            Exchange::End { ssrc, .. } => {
+                if let Some(idx) = self.participants.iter().position(|p| p.ssrc == ssrc) {
+                    let p = self.participants.remove(idx);
+                    info!(peer = p.name, "RTP-MIDI peer left");
+                    self.publish();
+                }
+            }
+            Exchange::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                if channel != Channel::Data || count > 1 {
+                    return;
+                }
+                // Answer synchronizations, the peer estimates the latency from them
+                let mut timestamps = timestamps;
+                timestamps[count as usize + 1] = timestamp(time);
+                let reply = Exchange::Sync {
+                    ssrc: self.ssrc,
+                    count: count + 1,
+                    timestamps,
+                };
+                if self.participants.iter().any(|p| p.ssrc == ssrc) {
+                    let _ = self.data.send_to(&reply.encode(), from);
+                }
+            }
+            Exchange::Feedback { ssrc, seq } => {
+                if let Some(p) = self.participants.iter().find(|p| p.ssrc == ssrc) {
+                    p.acked.store(seq as u32, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn invited(&mut self, channel: Channel, from: SocketAddr, token: u32, ssrc: u32, name: String) {
+        let accept = Exchange::Accept {
+            token,
+            ssrc: self.ssrc,
+            name: self.name.clone(),
+        };
+        let time = now().0;
+        match channel {
+            Channel::Control => {
+                match self.participants.iter_mut().find(|p| p.ssrc == ssrc) {
+                    Some(p) => p.control = from,
+                    None => self.participants.push(Participant {
+                        name,
+                        ssrc,
+                        control: from,
+                        data: None,
+                        initiated: false,
+                        last_seen: time,
+                        next_sync: time,
+                        acked: Arc::new(AtomicU32::new(NO_ACK)),
+                    }),
+                }
+                let _ = self.control.send_to(&accept.encode(), from);
+            }
+            Channel::Data => {
+                let Some(p) = self
+                    .participants
+                    .iter_mut()
+                    .find(|p| p.ssrc == ssrc && p.control.ip() == from.ip())
+                else {
+                    let reject = Exchange::Reject {
+                        token,
+                        ssrc: self.ssrc,
+                    };
+                    let _ = self.data.send_to(&reject.encode(), from);
+                    return;
+                };
+                if p.data.is_none() {
+                    info!(peer = p.name, addr = %from, "RTP-MIDI peer joined");
+                }
+                p.data = Some(from);
+                let _ = self.data.send_to(&accept.encode(), from);
+                // Both sides inviting each other, theirs came first
+                self.invites.retain(|i| i.addr != p.control);
+                self.publish();
+            }
+        }
+    }
+
+    fn accepted(
+        &mut self,
+        channel: Channel,
+        from: SocketAddr,
+        token: u32,
+        ssrc: u32,
+        name: String,
+    ) {
+        let Some(idx) = self.invites.iter().position(|i| i.token == token) else {
+            return;
+        };
+        let time = now().0;
+        match channel {
+            Channel::Control => {
+                // Continue with the data port right away
+                let invite = &mut self.invites[idx];
+                invite.channel = Channel::Data;
+                invite.next = time;
+            }
+            Channel::Data => {
+                let invite = self.invites.remove(idx);
+                if self.participants.iter().any(|p| p.ssrc == ssrc) {
+                    return;
+                }
+                info!(peer = name, addr = %from, "RTP-MIDI peer joined");
+                self.participants.push(Participant {
+                    name,
+                    ssrc,
+                    control: invite.addr,
+                    data: Some(from),
+                    initiated: true,
+                    last_seen: time,
+                    next_sync: time,
+                    acked: Arc::new(AtomicU32::new(NO_ACK)),
+                });
+                self.publish();
+            }
+        }
+    }
+
+    // Keep inviting configured peers that are not part of the session
+    fn invite(&mut self) {
+        let time = now().0;
+        for target in self.targets.iter() {
+            let joined = self.participants.iter().any(|p| p.control == *target);
+            if !joined && !self.invites.iter().any(|i| i.addr == *target) {
+                self.invites.push(Invite {
+                    addr: *target,
+                    token: random(),
+                    channel: Channel::Control,
+                    next: time,
+                    warned: false,
+                });
+            }
+        }
+        for invite in self.invites.iter_mut().filter(|i| i.next <= time) {
+            let msg = Exchange::Invitation {
+                token: invite.token,
+                ssrc: self.ssrc,
+                name: self.name.clone(),
+            }
+            .encode();
+            let result = match invite.channel {
+                Channel::Control => self.control.send_to(&msg, invite.addr),
+                Channel::Data => {
+                    let data = SocketAddr::new(invite.addr.ip(), invite.addr.port() + 1);
+                    self.data.send_to(&msg, data)
+                }
+            };
+            if let Err(e) = result {
+                if !invite.warned {
+                    warn!(peer = %invite.addr, error = ?e, "Failed to invite RTP-MIDI peer");
+                    invite.warned = true;
+                }
+            }
+            // Start over if the data port does not answer
+            invite.next = time + INVITE_RETRY.std_seconds();
+            invite.channel = Channel::Control;
+        }
+    }
+
+    // The initiator of a session synchronizes the clocks
+    fn synchronize(&mut self) {
+        let time = now().0;
+        for p in self.participants.iter_mut().filter(|p| p.initiated) {
+            let (Some(data), true) = (p.data, p.next_sync <= time) else {
+                continue;
+            };
+            let sync = Exchange::Sync {
+                ssrc: self.ssrc,
+                count: 0,
+                timestamps: [timestamp(time), 0, 0],
+            };
+            let _ = self.data.send_to(&sync.encode(), data);
+            p.next_sync = time + SYNC_INTERVAL.std_seconds();
+        }
+    }
+
+    fn expire(&mut self) {
+        let time = now().0;
+        let timeout = SESSION_TIMEOUT.std_seconds();
+        let before = self.participants.len();
+        self.participants.retain(|p| {
+            let alive = time.saturating_sub(p.last_seen) < timeout;
+            if !alive {
+                warn!(peer = p.name, "RTP-MIDI peer timed out");
+            }
+            alive
+        });
+        if self.participants.len() != before {
+            self.publish();
+        }
+    }
+
+    fn publish(&self) {
+        let peers = self
+            .participants
+            .iter()
+            .filter_map(|p| {
+                Some(RtpPeer {
+                    name: p.name.clone(),
+                    addr: p.data?,
+                    ssrc: p.ssrc,
+                    local_ssrc: self.ssrc,
+                    socket: self.data.clone(),
+                    acked: p.acked.clone(),
+                })
+            })
+            .collect();
+        if let Ok(mut shared) = self.peers.lock() {
+            *shared = peers;
+        }
+    }
+}
+
+/// AppleMIDI session protocol packets
+#[derive(Debug, PartialEq)]
+enum Exchange {
+    Invitation {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Accept {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Reject {
+        token: u32,
+        ssrc: u32,
+    },
+    End {
+        token: u32,
+        ssrc: u32,
+    },
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+    Feedback {
+        ssrc: u32,
+        seq: u16,
+    },
+}
+
+impl Exchange {
+    fn ssrc(&self) -> u32 {
+        match self {
+            Exchange::Invitation { ssrc, .. }
+            | Exchange::Accept { ssrc, .. }
+            | Exchange::Reject { ssrc, .. }
+            | Exchange::End { ssrc, .. }
+            | Exchange::Sync { ssrc, .. }
+            | Exchange::Feedback { ssrc, .. } => *ssrc,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0xFF, 0xFF];
+        let session = |buf: &mut Vec<u8>, cmd: &[u8], token: &u32, ssrc: &u32| {
+            buf.extend_from_slice(cmd);
+            buf.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            buf.extend_from_slice(&token.to_be_bytes());
+            buf.extend_from_slice(&ssrc.to_be_bytes());
+        };
+        match self {
+            Exchange::Invitation { token, ssrc, name } | Exchange::Accept { token, ssrc, name } => {
+                let cmd = match self {
+                    Exchange::Invitation { .. } => b"IN",
+                    _ => b"OK",
+                };
+                session(&mut buf, cmd, token, ssrc);
+                buf.extend_from_slice(name.as_bytes());
+                buf.push(0);
+            }
+            Exchange::Reject { token, ssrc } => session(&mut buf, b"NO", token, ssrc),
+            Exchange::End { token, ssrc } => session(&mut buf, b"BY", token, ssrc),
+            Exchange::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                buf.extend_from_slice(b"CK");
+                buf.extend_from_slice(&ssrc.to_be_bytes());
+                buf.extend_from_slice(&[*count, 0, 0, 0]);
+                for ts in timestamps {
+                    buf.extend_from_slice(&ts.to_be_bytes());
+                }
+            }
+            Exchange::Feedback { ssrc, seq } => {
+                buf.extend_from_slice(b"RS");
+                buf.extend_from_slice(&ssrc.to_be_bytes());
+                buf.extend_from_slice(&seq.to_be_bytes());
+                buf.extend_from_slice(&[0, 0]);
+            }
+        }
+        buf
+    }
+
+    fn decode(packet: &[u8]) -> Option<Exchange> {
+        let u32_at = |idx: usize| {
+            Some(u32::from_be_bytes(
+                packet.get(idx..idx + 4)?.try_into().ok()?,
+            ))
+        };
+        let u64_at = |idx: usize| {
+            Some(u64::from_be_bytes(
+                packet.get(idx..idx + 8)?.try_into().ok()?,
+            ))
+        };
+        if packet.get(..2)? != [0xFF, 0xFF] {
+            return None;
+        }
+        let name = || {
+            let name = packet.get(16..).unwrap_or_default();
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        };
+        let (token, ssrc) = (u32_at(8), u32_at(12));
+        let exchange = match packet.get(2..4)? {
+            b"IN" => Exchange::Invitation {
+                token: token?,
+                ssrc: ssrc?,
+                name: name(),
+            },
+            b"OK" => Exchange::Accept {
+                token: token?,
+                ssrc: ssrc?,
+                name: name(),
+            },
+            b"NO" => Exchange::Reject {
+                token: token?,
+                ssrc: ssrc?,
+            },
+            b"BY" => Exchange::End {
+                token: token?,
+                ssrc: ssrc?,
+            },
+            b"CK" => Exchange::Sync {
+                ssrc: u32_at(4)?,
+                count: *packet.get(8)?,
+                timestamps: [u64_at(12)?, u64_at(20)?, u64_at(28)?],
+            },
+            b"RS" => Exchange::Feedback {
+                ssrc: u32_at(4)?,
+                seq: u16::from_be_bytes(packet.get(8..10)?.try_into().ok()?),
+            },
+            _ => return None,
+        };
+        Some(exchange)
+    }
+}
+
+/* Sends every message in its own RTP packet, stamped with the time it was
+ * scheduled for rather than the time it left, so receivers that play packets
+ * by their time stamp see no jitter from the clock thread. Each packet carries
+ * a recovery journal of everything the peer did not confirm yet, a receiver
+ * that lost packets restores the transport state and programs from it. */
+pub struct RtpOut {
+    peer: RtpPeer,
+    seq: u16,
+    journal: Journal,
+}
+
+impl RtpOut {
+    pub fn new(peer: RtpPeer) -> RtpOut {
+        // Feedback for an earlier connection does not apply
+        peer.acked.store(NO_ACK, Ordering::Relaxed);
+        let seq = random() as u16;
+        RtpOut {
+            peer,
+            seq,
+            journal: Journal::new(seq),
+        }
+    }
+}
+
+impl MidiOut for RtpOut {
+    fn send(&mut self, msg: &[u8]) -> Result<()> {
+        self.send_at(now().0, msg)
+    }
+
+    fn send_at(&mut self, time: Duration, msg: &[u8]) -> Result<()> {
+        let acked = match self.peer.acked.load(Ordering::Relaxed) {
+            NO_ACK => None,
+            seq => Some(seq as u16),
+        };
+        self.journal.confirm(acked);
+        let packet = encode(
+            self.seq,
+            timestamp(time) as u32,
+            self.peer.local_ssrc,
+            msg,
+            &self.journal.encode(),
+        )?;
+        self.peer
+            .socket
+            .send_to(&packet, self.peer.addr)
+            .with_context(|| format!("Failed to send to {:?}", self.peer))?;
+        self.journal.record(self.seq, msg);
+        self.seq = self.seq.wrapping_add(1);
+        Ok(())
+    }
+}
+
+/// RTP packet with a MIDI command section holding msg, followed by the journal if not empty
+fn encode(seq: u16, timestamp: u32, ssrc: u32, msg: &[u8], journal: &[u8]) -> Result<Vec<u8>> {
+    if msg.len() > 0x0FFF {
+        bail!("Message too long for RTP-MIDI: {} bytes", msg.len());
+    }
+    let mut buf = vec![0x80, PAYLOAD_TYPE];
+    buf.extend_from_slice(&seq.to_be_bytes());
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.extend_from_slice(&ssrc.to_be_bytes());
+    let j = if journal.is_empty() { 0 } else { 0x40 };
+    match msg.len() {
+        len @ 0..=0x0F => buf.push(j | len as u8),
+        len => buf.extend_from_slice(&[0x80 | j | (len >> 8) as u8, len as u8]),
+    }
+    buf.extend_from_slice(msg);
+    buf.extend_from_slice(journal);
+    Ok(buf)
+}
+
+/* Recovery journal (RFC 6295) with the chapters a clock master needs: the
+ * sequencer state (system chapter Q) and the programs of all channels
+ * (channel chapter P). Chapters are left out once the peer confirmed a packet
+ * sent after their last change. Notes of the count-in are not journaled. */
+struct Journal {
+    first: u16,             // First packet sent, the checkpoint until the peer confirms one
+    acked: Option<u16>,     // Last packet the peer confirmed
+    running: bool,          // Start or Continue happened after the last Stop
+    downbeat: bool,         // No clock since the last Start or Continue
+    position: u32,          // Song position in MIDI clocks
+    sequencer: Option<u16>, // Packet of the last sequencer command
+    banks: [Bank; 16],
+    programs: [Option<Program>; 16],
+}
+
+/// Last bank select per channel
+#[derive(Clone, Copy, Default)]
+struct Bank {
+    msb: Option<u8>,
+    lsb: Option<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct Program {
+    seq: u16, // Packet of the program change
+    program: u8,
+    bank: Bank,
+}
+
+impl Journal {
+    fn new(first: u16) -> Journal {
+        Journal {
+            first,
+            acked: None,
+            running: false,
+            downbeat: false,
+            position: 0,
+            sequencer: None,
+            banks: [Bank::default(); 16],
+            programs: [None; 16],
+        }
+    }
+
+    fn confirm(&mut self, acked: Option<u16>) {
+        // Only packets we sent can be confirmed
+        if let Some(seq) = acked {
+            if !is_after(seq, self.first.wrapping_sub(1)) {
+                return;
+            }
+        }
+        self.acked = acked.or(self.acked);
+    }
+
+    fn record(&mut self, seq: u16, msg: &[u8]) {
+        let channel = (msg.first().copied().unwrap_or(0) & 0x0F) as usize;
+        match msg {
+            [0xFA] => {
+                self.running = true;
+                self.downbeat = true;
+                self.position = 0;
+            }
+            [0xFB] => {
+                self.running = true;
+                self.downbeat = true;
+            }
+            [0xFC] => self.running = false,
+            [0xF8] if self.running => match self.downbeat {
+                true => self.downbeat = false,
+                false => self.position += 1,
+            },
+            [0xF2, lsb, msb] => self.position = (*lsb as u32 | (*msb as u32) << 7) * 6,
+            [status, 0x00, msb] if status & 0xF0 == 0xB0 => {
+                self.banks[channel].msb = Some(*msb);
+                return;
+            }
+            [status, 0x20, lsb] if status & 0xF0 == 0xB0 => {
+                self.banks[channel].lsb = Some(*lsb);
+                return;
+            }
+            [status, program] if status & 0xF0 == 0xC0 => {
+                self.programs[channel] = Some(Program {
+                    seq,
+                    program: *program,
+                    bank: self.banks[channel],
+                });
+                return;
+            }
+            _ => return,
+        }
+        self.sequencer = Some(seq);
+    }
+
+    fn pending(&self, seq: u16) -> bool {
+        self.acked.is_none_or(|acked| is_after(seq, acked))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut system = Vec::new();
+        if self.sequencer.is_some_and(|seq| self.pending(seq)) {
+            let position = self.position & 0x7FFFF;
+            // Chapter Q: S N D C T TOP, CLOCK
+            let header = (self.running as u8) << 6
+                | (self.downbeat as u8) << 5
+                | 1 << 4
+                | (position >> 16) as u8;
+            system.push(header);
+            system.extend_from_slice(&(position as u16).to_be_bytes());
+        }
+        let channels: Vec<(usize, [u8; 3])> = self
+            .programs
+            .iter()
+            .enumerate()
+            .filter_map(|(channel, program)| {
+                let Program { seq, program, bank } = (*program)?;
+                if !self.pending(seq) {
+                    return None;
+                }
+                // Chapter P: S PROGRAM, B BANK-MSB, X BANK-LSB
+                let has_bank = bank.msb.is_some() || bank.lsb.is_some();
+                let chapter = [
+                    program & 0x7F,
+                    (has_bank as u8) << 7 | bank.msb.unwrap_or(0) & 0x7F,
+                    bank.lsb.unwrap_or(0) & 0x7F,
+                ];
+                Some((channel, chapter))
+            })
+            .collect();
+        if system.is_empty() && channels.is_empty() {
+            return Vec::new();
+        }
+
+        let checkpoint = self.acked.map_or(self.first, |seq| seq.wrapping_add(1));
+        let mut flags = 0;
+        if !system.is_empty() {
+            flags |= 0x40;
+        }
+        if !channels.is_empty() {
+            flags |= 0x20 | (channels.len() - 1) as u8;
+        }
+        let mut buf = vec![flags];
+        buf.extend_from_slice(&checkpoint.to_be_bytes());
+        if !system.is_empty() {
+            // System journal header: S D V Q F X LENGTH
+            let len = system.len() + 2;
+            buf.extend_from_slice(&[0x10 | (len >> 8) as u8, len as u8]);
+            buf.extend_from_slice(&system);
+        }
+        for (channel, chapter) in channels {
+            // Channel journal header: S CHAN H LENGTH, then the table of contents with P set.
+            // LENGTH counts the three header bytes and the chapter
+            let len = 3 + chapter.len();
+            buf.extend_from_slice(&[(channel as u8) << 3 | (len >> 8) as u8, len as u8, 0x80]);
+            buf.extend_from_slice(&chapter);
+        }
+        buf
+    }
+}
+
+// Sequence numbers wrap, a is after b if it is less than half the range ahead
+fn is_after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let mut journal = Journal::new(65535);
+        assert!(journal.encode().is_empty());
+        let packet = encode(65535, 7, 0xAABBCCDD, &[0xFA], &journal.encode()).unwrap();
+        assert_eq!(
+            packet,
+            [0x80, 0x61, 0xFF, 0xFF, 0, 0, 0, 7, 0xAA, 0xBB, 0xCC, 0xDD, 0x01, 0xFA]
+        );
+        journal.record(65535, &[0xFA]);
+        journal.record(0, &[0xF8]);
+        journal.record(1, &[0xF8]);
+        journal.record(2, &[0xB2, 0x00, 3]);
+        journal.record(3, &[0xC2, 9]);
+        // Running, one clock past the downbeat, program 9 in bank 3 on channel 3
+        assert_eq!(
+            journal.encode(),
+            [0x60, 0xFF, 0xFF, 0x10, 0x05, 0x50, 0x00, 0x01, 0x10, 0x06, 0x80, 0x09, 0x83, 0x00]
+        );
+
+        // Once confirmed only changes after the confirmed packet remain
+        journal.confirm(Some(2));
+        assert_eq!(
+            journal.encode(),
+            [0x20, 0x00, 0x03, 0x10, 0x06, 0x80, 0x09, 0x83, 0x00]
+        );
+        journal.confirm(Some(3));
+        assert!(journal.encode().is_empty());
+        journal.record(4, &[0xFC]);
+        let packet = encode(5, 0, 0, &[0xF0; 20], &journal.encode()).unwrap();
+        assert_eq!(packet[12..14], [0xC0, 20]);
+        assert_eq!(
+            packet[34..],
+            [0x40, 0x00, 0x04, 0x10, 0x05, 0x10, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_exchange() {
+        let invitation = Exchange::Invitation {
+            token: 1,
+            ssrc: 2,
+            name: "midimaxe".to_owned(),
+        };
+        let packet = invitation.encode();
+        assert_eq!(packet[..4], [0xFF, 0xFF, b'I', b'N']);
+        assert_eq!(Exchange::decode(&packet), Some(invitation));
+        let sync = Exchange::Sync {
+            ssrc: 3,
+            count: 1,
+            timestamps: [4, 5, 0],
+        };
+        assert_eq!(sync.encode().len(), 36);
+        assert_eq!(Exchange::decode(&sync.encode()), Some(sync));
+        assert_eq!(Exchange::decode(&[0x80, 0x61, 0, 0]), None);
+    }
+
+    #[test]
+    fn test_loopback_session() {
+        let a = RtpSession::spawn("a", 0, Vec::new()).unwrap();
+        let target = SocketAddr::from(([127, 0, 0, 1], a.port()));
+        let b = RtpSession::spawn("b", 0, vec![target]).unwrap();
+        let start = now().0;
+        while (a.peers().is_empty() || b.peers().is_empty()) && now().0 - start < 2.0.std_seconds()
+        {
+            thread::sleep(POLL.std_milliseconds());
+        }
+        assert_eq!(a.peers()[0].name, "b");
+        let peer = b.peers()[0].clone();
+        assert_eq!((peer.name.as_str(), peer.addr.port()), ("a", a.port() + 1));
+
+        // Clock sent to a peer arrives stamped with its scheduled time
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(1.0.std_seconds())).unwrap();
+        let mut out = RtpOut::new(RtpPeer {
+            addr: receiver.local_addr().unwrap(),
+            ..peer
+        });
+        out.send_at(Duration::from_millis(1500), &[0xF8]).unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(buf[4..8], 15000u32.to_be_bytes());
+        assert_eq!(buf[12..len], [0x01, 0xF8]);
+
+        drop(a);
+        let start = now().0;
+        while !b.peers().is_empty() && now().0 - start < 2.0.std_seconds() {
+            thread::sleep(POLL.std_milliseconds());
+        }
+        assert!(b.peers().is_empty());
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::midisync::{Cue, CueScope};
+
+/* A session file stores everything that is prepared ahead of a jam,
+ * e.g. the scenes with the patches every device should switch to */
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Session {
+    #[serde(default)]
+    pub scenes: Vec<Scene>,
+    /// Names of virtual output ports created at startup
+    #[serde(default)]
+    pub virtual_ports: Vec<String>,
+    /// Song Select sent to every port before it starts
+    pub song_select: Option<u8>,
+    /// Song Select for single ports, instead of the one for every port
+    #[serde(default)]
+    pub port_songs: Vec<PortSong>,
+    /// Ports that are started and stopped together
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    /// Count-in click of every port, a side stick on the drum channel if not set
+    pub click: Option<Click>,
+    /// Count-in click for single ports, instead of the one for every port
+    #[serde(default)]
+    pub port_clicks: Vec<PortClick>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scene {
+    pub name: String,
+    #[serde(default)]
+    pub programs: Vec<ProgramChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProgramChange {
+    /// Sent to all ports whose name contains this string
+    pub port: String,
+    /// MIDI channel 1-16
+    pub channel: u8,
+    pub program: u8,
+    pub bank_msb: Option<u8>,
+    pub bank_lsb: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PortSong {
+    /// Applies to all ports whose name contains this string
+    pub port: String,
+    pub song: u8,
+}
+
+/// Note a port plays as count-in click
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Click {
+    /// MIDI channel 1-16
+    pub channel: u8,
+    pub note: u8,
+    #[serde(default = "default_velocity")]
+    pub velocity: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PortClick {
+    /// Applies to all ports whose name contains this string
+    pub port: String,
+    #[serde(flatten)]
+    pub click: Click,
+}
+
+fn default_velocity() -> u8 {
+    100
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Group {
+    pub name: String,
+    /// Members are all ports whose name contains one of these strings
+    pub ports: Vec<String>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Session> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read session file {:?}", path))?;
+        let session: Session = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse session file {:?}", path))?;
+        session.validate()?;
+        Ok(session)
+    }
+
+    pub fn scene(&self, name: &str) -> Option<&Scene> {
+        self.scenes.iter().find(|s| s.name == name)
+    }
+
+    /// Song Select of the first entry matching port_name
+    pub fn port_song(&self, port_name: &str) -> Option<u8> {
+        self.port_songs
+            .iter()
+            .find(|s| port_name.contains(&s.port))
+            .map(|s| s.song)
+    }
+
+    /// Click of the first entry matching port_name, or the one for every port
+    pub fn port_click(&self, port_name: &str) -> Click {
+        self.port_clicks
+            .iter()
+            .find(|c| port_name.contains(&c.port))
+            .map(|c| c.click)
+            .or(self.click)
+            .unwrap_or_default()
+    }
+
+    /// Name of the first group port_name is a member of
+    pub fn port_group(&self, port_name: &str) -> Option<String> {
+        self.groups
+            .iter()
+            .find(|g| g.ports.iter().any(|p| port_name.contains(p)))
+            .map(|g| g.name.clone())
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (idx, group) in self.groups.iter().enumerate() {
+            if group.name.trim().is_empty() {
+                bail!("Group names must not be empty");
+            }
+            if self.groups[..idx].iter().any(|g| g.name == group.name) {
+                bail!("Duplicate group {:?}", group.name);
+            }
+        }
+        let songs = self.port_songs.iter().map(|s| s.song);
+        if let Some(song) = songs.chain(self.song_select).find(|s| *s > 127) {
+            bail!("Song Select must be 0-127, is {}", song);
+        }
+        let clicks = self.port_clicks.iter().map(|c| &c.click);
+        for click in clicks.chain(self.click.as_ref()) {
+            click.validate()?;
+        }
+        for (idx, name) in self.virtual_ports.iter().enumerate() {
+            if name.trim().is_empty() {
+                bail!("Virtual port names must not be empty");
+            }
+            if self.virtual_ports[..idx].contains(name) {
+                bail!("Duplicate virtual port {:?}", name);
+            }
+        }
+        for (idx, scene) in self.scenes.iter().enumerate() {
+            if self.scenes[..idx].iter().any(|s| s.name == scene.name) {
+                bail!("Duplicate scene {:?}", scene.name);
+            }
+            for program in scene.programs.iter() {
+                program
+                    .validate()
+                    .with_context(|| format!("Invalid program change in scene {}", scene.name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Click {
+    pub fn cue(&self, scope: CueScope) -> Cue {
+        Cue {
+            channel: (self.channel - 1) & 0x0F,
+            note: self.note,
+            velocity: self.velocity,
+            scope,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(1..=16).contains(&self.channel) {
+            bail!("Click channel must be 1-16, is {}", self.channel);
+        }
+        if self.note > 127 || !(1..=127).contains(&self.velocity) {
+            bail!("Click note must be 0-127 and velocity 1-127: {:?}", self);
+        }
+        Ok(())
+    }
+}
+
+// Side stick on the GM drum channel
+impl Default for Click {
+    fn default() -> Self {
+        Click {
+            channel: 10,
+            note: 37,
+            velocity: default_velocity(),
+        }
+    }
+}
+
+impl ProgramChange {
+    pub fn matches(&self, port_name: &str) -> bool {
+        port_name.contains(&self.port)
+    }
+
+    /// Bank select (if any) followed by the program change
+    pub fn to_midi(&self) -> Vec<Vec<u8>> {
+        let channel = (self.channel - 1) & 0x0F;
+        let mut msgs = Vec::new();
+        if let Some(msb) = self.bank_msb {
+            msgs.push(vec![0xB0 | channel, 0x00, msb]);
+        }
+        if let Some(lsb) = self.bank_lsb {
+            msgs.push(vec![0xB0 | channel, 0x20, lsb]);
+        }
+        msgs.push(vec![0xC0 | channel, self.program]);
+        msgs
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=16).contains(&self.channel) {
+            bail!("Channel must be 1-16, is {}", self.channel);
+        }
+        let data = [Some(self.program), self.bank_msb, self.bank_lsb];
+        if data.iter().flatten().any(|v| *v > 127) {
+            bail!("Program and bank must be 0-127: {:?}", self);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session() {
+        let session: Session = toml::from_str(
+            r#"
+            virtual_ports = ["Bitwig", "VCV Rack"]
+            song_select = 3
+            port_songs = [{ port = "Digitakt", song = 7 }]
+            click = { channel = 16, note = 76 }
+            port_clicks = [{ port = "TR-8", channel = 10, note = 75, velocity = 127 }]
+            groups = [
+                { name = "Drums", ports = ["Digitakt", "TR-8"] },
+                { name = "Pads", ports = ["Hydrasynth"] },
+            ]
+
+            [[scenes]]
+            name = "Intro"
+            programs = [
+                { port = "Digitone", channel = 1, program = 5 },
+                { port = "Minilogue", channel = 2, program = 12, bank_msb = 1, bank_lsb = 3 },
+            ]
+
+            [[scenes]]
+            name = "Empty"
+            "#,
+        )
+        .unwrap();
+        assert!(session.validate().is_ok());
+        assert_eq!(session.scenes.len(), 2);
+        assert_eq!(session.virtual_ports, vec!["Bitwig", "VCV Rack"]);
+        assert_eq!(session.song_select, Some(3));
+        assert_eq!(session.port_song("Elektron Digitakt MIDI 1"), Some(7));
+        assert_eq!(session.port_song("Digitone"), None);
+        assert_eq!(session.port_group("TR-8 MIDI 1").as_deref(), Some("Drums"));
+        assert_eq!(session.port_group("Digitone"), None);
+        assert_eq!(
+            session
+                .port_click("TR-8 MIDI 1")
+                .cue(CueScope::Port)
+                .channel,
+            9
+        );
+        assert_eq!(session.port_click("TR-8 MIDI 1").velocity, 127);
+        assert_eq!(session.port_click("Digitone").note, 76);
+        assert_eq!(session.port_click("Digitone").velocity, 100);
+        assert_eq!(Session::default().port_click("Digitone"), Click::default());
+        assert!(session.scene("Empty").unwrap().programs.is_empty());
+
+        let intro = session.scene("Intro").unwrap();
+        assert!(intro.programs[0].matches("Digitone:Digitone MIDI 1 24:0"));
+        assert_eq!(intro.programs[0].to_midi(), vec![vec![0xC0, 5]]);
+        assert_eq!(
+            intro.programs[1].to_midi(),
+            vec![vec![0xB1, 0x00, 1], vec![0xB1, 0x20, 3], vec![0xC1, 12]]
+        );
+    }
+
+    #[test]
+    fn test_invalid_program() {
+        let mut pc = ProgramChange {
+            port: "x".to_owned(),
+            channel: 0,
+            program: 1,
+            bank_msb: None,
+            bank_lsb: None,
+        };
+        assert!(pc.validate().is_err());
+        pc.channel = 16;
+        assert!(pc.validate().is_ok());
+        pc.bank_lsb = Some(128);
+        assert!(pc.validate().is_err());
+
+        let session: Session = toml::from_str(r#"virtual_ports = ["A", "A"]"#).unwrap();
+        assert!(session.validate().is_err());
+        let session: Session = toml::from_str(
+            r#"
+            [[scenes]]
+            name = "Intro"
+            [[scenes]]
+            name = "Intro"
+            "#,
+        )
+        .unwrap();
+        assert!(session.validate().is_err());
+        let session: Session = toml::from_str("click = { channel = 0, note = 37 }").unwrap();
+        assert!(session.validate().is_err());
+        let session: Session = toml::from_str("song_select = 128").unwrap();
+        assert!(session.validate().is_err());
+        let session: Session = toml::from_str(
+            r#"groups = [{ name = "A", ports = ["x"] }, { name = "A", ports = ["y"] }]"#,
+        )
+        .unwrap();
+        assert!(session.validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::multisync::{Settings, TimeSignature};
+use crate::session::ProgramChange;
+
+/* A setlist contains the recurring songs of a jam. Arming a song applies
+ * its settings at the next quantum boundary. */
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Setlist {
+    #[serde(default)]
+    pub songs: Vec<Song>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Song {
+    pub name: String,
+    pub bpm: f64,
+    pub quantum: Option<f64>,
+    pub time_signature: Option<TimeSignature>,
+    /// Scene from the session file that is recalled with the song
+    pub scene: Option<String>,
+    #[serde(default)]
+    pub programs: Vec<ProgramChange>,
+}
+
+impl Setlist {
+    pub fn load(path: &Path) -> Result<Setlist> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read setlist {:?}", path))?;
+        let setlist: Setlist = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse setlist {:?}", path))?;
+        for song in setlist.songs.iter() {
+            song.validate()
+                .with_context(|| format!("Invalid song {}", song.name))?;
+        }
+        Ok(setlist)
+    }
+}
+
+impl Song {
+    /* Settings for this song. A quantum or time signature the song does not define
+     * is taken from the initial settings, not from the song played before. */
+    pub fn settings(&self, current: &Settings, initial: &Settings) -> Settings {
+        Settings {
+            bpm: self.bpm,
+            quantum: self.quantum.unwrap_or(initial.quantum),
+            time_signature: self.time_signature.unwrap_or(initial.time_signature),
+            ..current.clone()
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.quantum.is_some_and(|q| q < 1.0) {
+            bail!("Quantum must be at least 1, is {:?}", self.quantum);
+        }
+        for program in self.programs.iter() {
+            program.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_song_settings() {
+        let setlist: Setlist = toml::from_str(
+            r#"
+            [[songs]]
+            name = "Acid"
+            bpm = 128.5
+            quantum = 32
+            time_signature = "7/8"
+            scene = "Intro"
+
+            [[songs]]
+            name = "Dub"
+            bpm = 70
+            programs = [{ port = "Digitone", channel = 1, program = 3 }]
+            "#,
+        )
+        .unwrap();
+        let base = Settings::new(130.0, 16.0, None);
+
+        let acid = setlist.songs[0].settings(&base, &base);
+        assert_eq!(acid.bpm, 128.5);
+        assert_eq!(acid.quantum, 32.0);
+        assert_eq!(acid.time_signature, TimeSignature::new(7, 8));
+        assert_eq!(setlist.songs[0].scene.as_deref(), Some("Intro"));
+
+        let dub = setlist.songs[1].settings(&acid, &base);
+        assert_eq!(dub.bpm, 70.0);
+        assert_eq!(dub.quantum, 16.0);
+        assert_eq!(dub.time_signature, TimeSignature::new(4, 4));
+        assert_eq!(setlist.songs[1].programs.len(), 1);
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{bail, Context, Result};
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::path::Path;
+
+use crate::multisync::{Settings, TimeSignature};
+
+const DEFAULT_BPM: f64 = 120.0; // Tempo of an SMF without tempo events
+
+/// Tempo and time signature in effect from a position on the beat grid onwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempoPoint {
+    pub beat: f64, // Position in quarters
+    pub bpm: f64,
+    pub time_signature: TimeSignature,
+}
+
+/* A tempo map is a pre-planned tempo arrangement, taken from the tempo and
+ * time signature meta events of a Standard MIDI File. Points are sorted by
+ * position and the first one is always at beat 0. */
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    pub points: Vec<TempoPoint>,
+}
+
+impl TempoMap {
+    pub fn load(path: &Path) -> Result<TempoMap> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let smf = Smf::parse(&data).with_context(|| format!("Failed to parse {:?}", path))?;
+        TempoMap::from_smf(&smf).with_context(|| format!("Invalid tempo map {:?}", path))
+    }
+
+    pub fn from_smf(smf: &Smf) -> Result<TempoMap> {
+        let ppq = match smf.header.timing {
+            Timing::Metrical(ppq) if ppq.as_int() > 0 => ppq.as_int() as f64,
+            timing => bail!("Only metrical timing is supported, file uses {:?}", timing),
+        };
+
+        // Meta events of all tracks, as (tick, tempo, time signature)
+        let mut events = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut tick = 0u64;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::Tempo(us_per_quarter)) => {
+                        if us_per_quarter.as_int() == 0 {
+                            bail!("Tempo of 0 microseconds per quarter at tick {}", tick);
+                        }
+                        let bpm = 60_000_000.0 / us_per_quarter.as_int() as f64;
+                        events.push((tick, Some(bpm), None));
+                    }
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(num, den, _, _)) => {
+                        if num == 0 || den > 7 {
+                            bail!("Invalid time signature {}/2^{} at tick {}", num, den, tick);
+                        }
+                        let signature = TimeSignature::new(num, 1 << den);
+                        events.push((tick, None, Some(signature)));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        events.sort_by_key(|(tick, _, _)| *tick);
+
+        let mut points = vec![TempoPoint {
+            beat: 0.0,
+            bpm: DEFAULT_BPM,
+            time_signature: TimeSignature::new(4, 4),
+        }];
+        for (tick, bpm, signature) in events {
+            let beat = tick as f64 / ppq;
+            let last = *points.last().unwrap();
+            let point = TempoPoint {
+                beat,
+                bpm: bpm.unwrap_or(last.bpm),
+                time_signature: signature.unwrap_or(last.time_signature),
+            };
+            if point == last {
+                continue;
+            }
+            if last.beat == beat {
+                *points.last_mut().unwrap() = point;
+            } else {
+                points.push(point);
+            }
+        }
+        Ok(TempoMap { points })
+    }
+
+    /// Tempo and time signature at a position
+    pub fn at(&self, beat: f64) -> TempoPoint {
+        let idx = self.points.partition_point(|p| p.beat <= beat);
+        self.points[idx.saturating_sub(1)]
+    }
+
+    /// Time in seconds from the start to a position, following the tempo changes before it
+    pub fn seconds_at(&self, beat: f64) -> f64 {
+        let ends = self.points.iter().skip(1).map(|p| p.beat).chain([f64::MAX]);
+        self.points
+            .iter()
+            .zip(ends)
+            .take_while(|(point, _)| point.beat < beat)
+            .map(|(point, end)| (end.min(beat) - point.beat) * 60.0 / point.bpm)
+            .sum()
+    }
+
+    /// First change after a position
+    pub fn next(&self, beat: f64) -> Option<TempoPoint> {
+        let idx = self.points.partition_point(|p| p.beat <= beat);
+        self.points.get(idx).copied()
+    }
+
+    /// Bar at a position, counted from 0, the fraction is the part of the bar that passed
+    pub fn bar_at(&self, beat: f64) -> f64 {
+        let (bar, point) = self
+            .signature_bars()
+            .take_while(|(_, p)| p.beat <= beat)
+            .last()
+            .unwrap();
+        bar + (beat.max(0.0) - point.beat) / point.time_signature.bar_length()
+    }
+
+    /// Position in quarters where a bar starts
+    pub fn beat_of_bar(&self, bar: f64) -> f64 {
+        let (first, point) = self
+            .signature_bars()
+            .take_while(|(b, _)| *b <= bar)
+            .last()
+            .unwrap();
+        point.beat + (bar.max(0.0) - first) * point.time_signature.bar_length()
+    }
+
+    /* Points where the time signature changes, with the bar they start. A change
+     * that does not fall on a bar line cuts the bar before it short. */
+    fn signature_bars(&self) -> impl Iterator<Item = (f64, TempoPoint)> + '_ {
+        let mut bar = 0.0;
+        let mut last: Option<TempoPoint> = None;
+        self.points.iter().filter_map(move |point| {
+            match last {
+                Some(l) if l.time_signature == point.time_signature => return None,
+                Some(l) => {
+                    bar += ((point.beat - l.beat) / l.time_signature.bar_length() - 1e-9).ceil()
+                }
+                None => (),
+            }
+            last = Some(*point);
+            Some((bar, *point))
+        })
+    }
+}
+
+impl TempoPoint {
+    pub fn settings(&self, base: &Settings) -> Settings {
+        Settings {
+            bpm: self.bpm,
+            time_signature: self.time_signature,
+            ..base.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u15, u24, u28};
+    use midly::{Format, Header, TrackEvent};
+
+    fn meta(delta: u32, msg: MetaMessage) -> TrackEvent {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(msg),
+        }
+    }
+
+    #[test]
+    fn test_tempo_map() {
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(480)),
+        ));
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(u24::new(500_000))),
+            meta(0, MetaMessage::TimeSignature(4, 2, 24, 8)),
+            // 90 BPM after 4 bars
+            meta(480 * 16, MetaMessage::Tempo(u24::new(666_667))),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        // 7/8 on beat 32, in a different track
+        smf.tracks.push(vec![
+            meta(480 * 32, MetaMessage::TimeSignature(7, 3, 24, 8)),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        let mut data = Vec::new();
+        smf.write_std(&mut data).unwrap();
+        let map = TempoMap::from_smf(&Smf::parse(&data).unwrap()).unwrap();
+
+        assert_eq!(map.points.len(), 3);
+        assert_eq!(map.at(0.0).bpm, 120.0);
+        assert_eq!(map.at(15.9).bpm, 120.0);
+        assert_eq!((map.at(16.0).bpm * 100.0).round(), 9000.0);
+        assert_eq!(map.at(40.0).time_signature, TimeSignature::new(7, 8));
+        assert_eq!(map.next(0.0).unwrap().beat, 16.0);
+        assert_eq!(map.next(16.0).unwrap().beat, 32.0);
+        assert!(map.next(32.0).is_none());
+        // 8 s of 120 BPM, then 90 BPM
+        assert_eq!(map.seconds_at(8.0), 4.0);
+        assert_eq!((map.seconds_at(19.0) * 1000.0).round(), 10_000.0);
+        // 8 bars of 4/4, then bars of 7/8
+        assert_eq!(map.bar_at(30.0), 7.5);
+        assert_eq!(map.bar_at(35.5), 9.0);
+        assert_eq!(map.beat_of_bar(10.0), 39.0);
+    }
+
+    #[test]
+    fn test_bars_off_bar_line() {
+        let point = |beat, num, den| TempoPoint {
+            beat,
+            bpm: 120.0,
+            time_signature: TimeSignature::new(num, den),
+        };
+        // 3/4 starts in the middle of the second 4/4 bar, that bar is cut short
+        let map = TempoMap {
+            points: vec![point(0.0, 4, 4), point(6.0, 3, 4), point(9.0, 3, 4)],
+        };
+        assert_eq!(map.bar_at(5.0), 1.25);
+        assert_eq!(map.bar_at(6.0), 2.0);
+        assert_eq!(map.bar_at(10.5), 3.5);
+        assert_eq!(map.beat_of_bar(1.0), 4.0);
+        assert_eq!(map.beat_of_bar(3.0), 9.0);
+    }
+
+    #[test]
+    fn test_timecode_rejected() {
+        let smf = Smf::new(Header::new(
+            Format::SingleTrack,
+            Timing::Timecode(midly::Fps::Fps25, 40),
+        ));
+        assert!(TempoMap::from_smf(&smf).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use anyhow::{bail, Result};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::midisync::{Cue, Heartbeat, MidiSync, MidiSyncState, Rate, SyncMode};
+use crate::timing::{LatenessSummary, SyncMetrics};
+
+pub type SyncId = u64;
+
+/// Operations on a single MidiSync, mirroring its methods
+#[derive(Debug)]
+pub enum SyncOp {
+    Start(Duration, Duration),
+    Locate(u16),
+    Stop,
+    Update(f64, Option<f64>, Rate, SyncMode, Heartbeat),
+    Retime(Duration, f64, Option<f64>),
+    Schedule(Duration, Vec<u8>),
+    CountIn(Duration, Duration, u32, Cue),
+    CancelCountIn(Duration),
+    ScheduleScene(Duration, Vec<u8>),
+    CancelScene,
+    Fail(String),
+}
+
+pub enum TickCommand {
+    Add(SyncId, Box<MidiSync>),
+    Remove(SyncId),
+    Sync(SyncId, u64, SyncOp),
+    Shutdown,
+}
+
+pub enum TickEvent {
+    /// State of a sync after the operation with the given sequence number
+    State(SyncId, u64, MidiSyncState, Option<Duration>),
+    /// Tick statistics of a sync
+    SyncMetrics(SyncId, SyncMetrics),
+    /// Lateness of the timing thread
+    Lateness(LatenessSummary),
+    /// Sync that was removed, dropped by the receiver to close its port
+    Removed(Box<MidiSync>),
+}
+
+struct TickSync {
+    id: SyncId,
+    seq: u64,
+    sync: Box<MidiSync>, // Boxed as handed over, so removing it does not allocate
+}
+
+/* The tick loop owns all MidiSyncs and does nothing but sending their messages
+ * on time. Everything else happens on the control thread, which hands over
+ * changes as commands and gets state changes reported back. */
+pub struct TickLoop {
+    syncs: Vec<TickSync>,
+    cmd: Receiver<TickCommand>,
+    events: Sender<TickEvent>,
+    shutdown: bool,
+}
+
+/// Control side of the tick loop
+pub struct TickCtrl {
+    cmd: Sender<TickCommand>,
+    events: Receiver<TickEvent>,
+    next_id: SyncId,
+}
+
+/* Stands in for a MidiSync that runs in the tick loop. The state is predicted
+ * from the operations sent and corrected by the reports of the tick loop,
+ * reports for older operations are ignored. */
+pub struct SyncHandle {
+    id: SyncId,
+    seq: u64,
+    cmd: Sender<TickCommand>,
+    state: MidiSyncState,
+    start_time: Option<Duration>,
+}
+
+impl TickLoop {
+    pub fn new() -> (TickLoop, TickCtrl) {
+        let (s_cmd, r_cmd) = unbounded();
+        let (s_events, r_events) = unbounded();
+        (
+            TickLoop {
+                syncs: Vec::new(),
+                cmd: r_cmd,
+                events: s_events,
+                shutdown: false,
+            },
+            TickCtrl {
+                cmd: s_cmd,
+                events: r_events,
+                next_id: 0,
+            },
+        )
+    }
+
+    /// Run all syncs, returns the time of the next event
+    pub fn run(&mut self) -> Option<Duration> {
+        while let Ok(cmd) = self.cmd.try_recv() {
+            self.process_cmd(cmd);
+        }
+        let mut next_event: Option<Duration> = None;
+        for tick_sync in self.syncs.iter_mut() {
+            let before = tick_sync.sync.state();
+            let next = tick_sync.sync.run();
+            if std::mem::discriminant(&before) != std::mem::discriminant(&tick_sync.sync.state()) {
+                report(&self.events, tick_sync);
+            }
+            next_event = match (next_event, next) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        next_event
+    }
+
+    /// Wait for a command up to timeout, returns true if one was processed
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        match self.cmd.recv_timeout(timeout) {
+            Ok(cmd) => {
+                self.process_cmd(cmd);
+                true
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.shutdown = true;
+                true
+            }
+        }
+    }
+
+    pub fn report_metrics(&mut self, lateness: LatenessSummary) {
+        let _ = self.events.send(TickEvent::Lateness(lateness));
+        for tick_sync in self.syncs.iter_mut() {
+            let metrics = tick_sync.sync.metrics();
+            let _ = self
+                .events
+                .send(TickEvent::SyncMetrics(tick_sync.id, metrics));
+        }
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown
+    }
+
+    /* Closing a port can take a round trip to the JACK server or ALSA, which would hold
+     * up the ticks of all other ports. The control thread drops removed syncs instead. */
+    fn remove(&mut self, id: SyncId) {
+        if let Some(idx) = self.syncs.iter().position(|s| s.id == id) {
+            let removed = self.syncs.remove(idx);
+            let _ = self.events.send(TickEvent::Removed(removed.sync));
+        }
+    }
+
+    fn process_cmd(&mut self, cmd: TickCommand) {
+        match cmd {
+            TickCommand::Add(id, sync) => {
+                self.remove(id);
+                self.syncs.push(TickSync { id, seq: 0, sync });
+            }
+            TickCommand::Remove(id) => self.remove(id),
+            TickCommand::Sync(id, seq, op) => {
+                let Some(tick_sync) = self.syncs.iter_mut().find(|s| s.id == id) else {
+                    return;
+                };
+                let sync = &mut tick_sync.sync;
+                match op {
+                    SyncOp::Start(time, timeline) => sync.start(Some(time), timeline),
+                    SyncOp::Locate(sixteenths) => sync.locate(sixteenths),
+                    SyncOp::Stop => sync.stop(),
+                    SyncOp::Update(bpm, tpqn, rate, mode, heartbeat) => {
+                        sync.update(bpm, tpqn, rate, mode, heartbeat).unwrap_or(())
+                    }
+                    SyncOp::Retime(at, bpm, tpqn) => sync.retime(at, bpm, tpqn),
+                    SyncOp::Schedule(time, msg) => sync.schedule(time, msg),
+                    SyncOp::CountIn(start_time, beat, beats, cue) => {
+                        sync.count_in(start_time, beat, beats, &cue)
+                    }
+                    SyncOp::CancelCountIn(start_time) => sync.cancel_count_in(start_time),
+                    SyncOp::ScheduleScene(time, msg) => sync.schedule_scene(time, msg),
+                    SyncOp::CancelScene => sync.cancel_scene(),
+                    SyncOp::Fail(error) => sync.fail(error),
+                }
+                tick_sync.seq = seq;
+                report(&self.events, tick_sync);
+            }
+            TickCommand::Shutdown => self.shutdown = true,
+        }
+    }
+}
+
+fn report(events: &Sender<TickEvent>, tick_sync: &TickSync) {
+    let _ = events.send(TickEvent::State(
+        tick_sync.id,
+        tick_sync.seq,
+        tick_sync.sync.state(),
+        tick_sync.sync.start_time(),
+    ));
+}
+
+impl TickCtrl {
+    /// Hand a sync over to the tick loop
+    pub fn add(&mut self, sync: MidiSync) -> SyncHandle {
+        self.next_id += 1;
+        let handle = SyncHandle {
+            id: self.next_id,
+            seq: 0,
+            cmd: self.cmd.clone(),
+            state: sync.state(),
+            start_time: sync.start_time(),
+        };
+        let _ = self.cmd.send(TickCommand::Add(handle.id, Box::new(sync)));
+        handle
+    }
+
+    pub fn get_event(&self) -> Option<TickEvent> {
+        self.events.try_recv().ok()
+    }
+
+    pub fn shutdown(&self) {
+        let _ = self.cmd.send(TickCommand::Shutdown);
+    }
+}
+
+impl SyncHandle {
+    pub fn id(&self) -> SyncId {
+        self.id
+    }
+
+    pub fn start(&mut self, start_time: Option<Duration>, timeline: Duration) {
+        if let (MidiSyncState::Stopped, Some(time)) = (&self.state, start_time) {
+            self.state = MidiSyncState::Starting;
+            self.start_time = Some(time);
+            self.send(SyncOp::Start(time, timeline));
+        }
+    }
+
+    pub fn locate(&mut self, sixteenths: u16) {
+        self.send(SyncOp::Locate(sixteenths));
+    }
+
+    pub fn stop(&mut self) {
+        if !matches!(self.state, MidiSyncState::Error(_)) {
+            self.state = MidiSyncState::Stopped;
+            self.start_time = None;
+        }
+        self.send(SyncOp::Stop);
+    }
+
+    pub fn update(
+        &mut self,
+        bpm: f64,
+        tpqn: Option<f64>,
+        rate: Rate,
+        mode: SyncMode,
+        heartbeat: Heartbeat,
+    ) -> Result<()> {
+        match self.state {
+            MidiSyncState::Stopped => {
+                self.send(SyncOp::Update(bpm, tpqn, rate, mode, heartbeat));
+                Ok(())
+            }
+            _ => bail!(
+                "Update only valid in Stopped state, was in: {:?}",
+                self.state
+            ),
+        }
+    }
+
+    pub fn retime(&mut self, at: Duration, bpm: f64, tpqn: Option<f64>) {
+        self.send(SyncOp::Retime(at, bpm, tpqn));
+    }
+
+    pub fn schedule(&mut self, time: Duration, msg: Vec<u8>) {
+        self.send(SyncOp::Schedule(time, msg));
+    }
+
+    pub fn count_in(&mut self, start_time: Duration, beat: Duration, beats: u32, cue: &Cue) {
+        self.send(SyncOp::CountIn(start_time, beat, beats, *cue));
+    }
+
+    pub fn cancel_count_in(&mut self, start_time: Duration) {
+        self.send(SyncOp::CancelCountIn(start_time));
+    }
+
+    pub fn schedule_scene(&mut self, time: Duration, msg: Vec<u8>) {
+        self.send(SyncOp::ScheduleScene(time, msg));
+    }
+
+    pub fn cancel_scene(&mut self) {
+        self.send(SyncOp::CancelScene);
+    }
+
+    pub fn fail(&mut self, error: String) {
+        self.state = MidiSyncState::Error(error.clone());
+        self.start_time = None;
+        self.send(SyncOp::Fail(error));
+    }
+
+    pub fn state(&self) -> MidiSyncState {
+        self.state.clone()
+    }
+
+    pub fn start_time(&self) -> Option<Duration> {
+        self.start_time
+    }
+
+    /// Apply a state reported by the tick loop, returns the previous state if it changed
+    pub fn apply(
+        &mut self,
+        seq: u64,
+        state: MidiSyncState,
+        start_time: Option<Duration>,
+    ) -> Option<MidiSyncState> {
+        if seq < self.seq {
+            return None;
+        }
+        self.start_time = start_time;
+        if std::mem::discriminant(&state) == std::mem::discriminant(&self.state) {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.state, state))
+    }
+
+    fn send(&mut self, op: SyncOp) {
+        self.seq += 1;
+        let _ = self.cmd.send(TickCommand::Sync(self.id, self.seq, op));
+    }
+}
+
+impl Drop for SyncHandle {
+    fn drop(&mut self) {
+        let _ = self.cmd.send(TickCommand::Remove(self.id));
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use time::ext::NumericalStdDuration;
+use tracing::{info, warn};
+use utils::circularbuffer::CircularBuffer;
+use utils::programclock::now;
+
+use crate::tickloop::TickLoop;
+
+const SPIN: f64 = 1.0; // Time before a deadline that is spent spinning instead of sleeping, in ms
+const IDLE_SLEEP: f64 = 10.0; // Longest wait in ms when no event is scheduled
+const LATENESS_WINDOW: usize = 2000; // Number of wakeups the lateness statistics are taken over
+const REPORT_INTERVAL: f64 = 1.0; // Seconds between lateness reports to the control thread
+
+pub struct TimingOptions {
+    /// Request SCHED_FIFO scheduling and lock all memory
+    pub realtime: bool,
+    pub priority: i32,
+}
+
+/// Statistics of how late events happened compared to their deadlines
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatenessSummary {
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    pub p99: Duration,
+    /// Mean difference in lateness between consecutive events
+    pub jitter: Duration,
+}
+
+pub struct Lateness {
+    window: CircularBuffer<Duration>,
+    sorted: Vec<Duration>, // Allocated once, the summary runs on the clock thread
+}
+
+/// Upper bounds of the lateness histogram buckets in seconds
+pub const LATENESS_BUCKETS: [f64; 8] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025];
+
+/// Lateness of all events since creation, counts are per bucket (not cumulative)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub buckets: [u64; LATENESS_BUCKETS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+/// Tick statistics of a single sync as reported by the tick loop
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncMetrics {
+    /// Recent ticks, reset on start
+    pub lateness: LatenessSummary,
+    /// All ticks sent on the connection
+    pub histogram: Histogram,
+}
+
+/* The timing thread only runs the tick loop. To hit tick deadlines precisely
+ * it waits for commands until shortly before the deadline and spins the rest
+ * of the way, the OS scheduler usually wakes threads up too late otherwise. */
+pub fn spawn(mut ticks: TickLoop, options: TimingOptions) -> Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("midimaxe clock".to_owned())
+        .spawn(move || {
+            if options.realtime {
+                enable_realtime(options.priority);
+            }
+            let mut lateness = Lateness::new(LATENESS_WINDOW);
+            let mut last_report = now();
+            let spin = SPIN.std_milliseconds();
+            while !ticks.is_shut_down() {
+                let next_event = ticks.run();
+                let until = match next_event {
+                    Some(deadline) => deadline.min(now().0 + IDLE_SLEEP.std_milliseconds()),
+                    None => now().0 + IDLE_SLEEP.std_milliseconds(),
+                };
+                let remaining = until.saturating_sub(now().0);
+                // An event that is already due is sent by the next run, but never spin on it
+                if remaining.is_zero() {
+                    thread::yield_now();
+                    continue;
+                }
+                // A new command can change the next event, start over
+                if remaining > spin && ticks.wait(remaining - spin) {
+                    continue;
+                }
+                while now().0 < until {
+                    std::hint::spin_loop();
+                }
+                if next_event == Some(until) {
+                    lateness.add(now().0 - until);
+                }
+                if now().0 - last_report.0 > REPORT_INTERVAL.std_seconds() {
+                    ticks.report_metrics(lateness.summary());
+                    last_report = now();
+                }
+            }
+            info!(lateness = ?lateness.summary(), "Clock thread stopped");
+        })
+        .context("Failed to start clock thread")
+}
+
+#[cfg(target_os = "linux")]
+fn enable_realtime(priority: i32) {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: Only changes the scheduling of the calling thread
+    let err =
+        unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) };
+    if err == 0 {
+        info!(priority, "Clock thread uses SCHED_FIFO");
+    } else {
+        let e = std::io::Error::from_raw_os_error(err);
+        warn!(priority, error = ?e, "Failed to set SCHED_FIFO scheduling");
+    }
+
+    // SAFETY: Locking memory has no effect on memory safety
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0 {
+        info!("Memory locked");
+    } else {
+        let e = std::io::Error::last_os_error();
+        warn!(error = ?e, "Failed to lock memory");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_realtime(_priority: i32) {
+    warn!("Realtime scheduling is only supported on Linux");
+}
+
+impl Lateness {
+    pub fn new(window: usize) -> Self {
+        Lateness {
+            window: CircularBuffer::new(window),
+            sorted: Vec::with_capacity(window),
+        }
+    }
+
+    pub fn add(&mut self, lateness: Duration) {
+        self.window.add(lateness);
+    }
+
+    pub fn summary(&mut self) -> LatenessSummary {
+        let buf = self.window.get_buf();
+        if buf.is_empty() {
+            return LatenessSummary::default();
+        }
+        let sorted = &mut self.sorted;
+        sorted.clear();
+        sorted.extend(buf.iter().copied());
+        // Unlike sort, sort_unstable does not allocate
+        sorted.sort_unstable();
+        let p99 = ((sorted.len() - 1) as f64 * 0.99).round() as usize;
+        let jitter = match buf.len() {
+            1 => Duration::ZERO,
+            len => {
+                let diffs = buf
+                    .iter()
+                    .zip(buf.iter().skip(1))
+                    .map(|(a, b)| a.abs_diff(*b));
+                diffs.sum::<Duration>() / (len - 1) as u32
+            }
+        };
+        LatenessSummary {
+            min: sorted[0],
+            mean: buf.iter().sum::<Duration>() / buf.len() as u32,
+            max: sorted[sorted.len() - 1],
+            p99: sorted[p99],
+            jitter,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.window.clear();
+    }
+}
+
+impl Histogram {
+    pub fn add(&mut self, lateness: Duration) {
+        let secs = lateness.as_secs_f64();
+        if let Some(idx) = LATENESS_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += lateness;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lateness() {
+        let mut lateness = Lateness::new(3);
+        assert_eq!(lateness.summary(), LatenessSummary::default());
+        for us in [900, 100, 200, 300] {
+            lateness.add(Duration::from_micros(us));
+        }
+        let summary = lateness.summary();
+        assert_eq!(summary.min, Duration::from_micros(100));
+        assert_eq!(summary.mean, Duration::from_micros(200));
+        assert_eq!(summary.max, Duration::from_micros(300));
+        assert_eq!(summary.p99, Duration::from_micros(300));
+        assert_eq!(summary.jitter, Duration::from_micros(100));
+
+        // One late tick in 100 does not show in p99, alternating lateness is jitter
+        let mut lateness = Lateness::new(200);
+        lateness.add(Duration::from_micros(5000));
+        for us in 1..200 {
+            lateness.add(Duration::from_micros(us % 2 * 1000));
+        }
+        let summary = lateness.summary();
+        assert_eq!(summary.max, Duration::from_micros(5000));
+        assert_eq!(summary.p99, Duration::from_micros(1000));
+        assert!(summary.jitter > Duration::from_micros(1000));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        for us in [50, 100, 101, 2000, 100_000] {
+            histogram.add(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.buckets, [2, 1, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(histogram.count, 5);
+        assert_eq!(histogram.sum, Duration::from_micros(102_251));
+    }
+}
@@ -1,6 +1,10 @@
-use crate::midisync::MidiSyncState;
+use crate::eventlog::LogLine;
+use crate::midisync::{CueScope, Heartbeat, MidiSyncState, Rate, SyncMode, DEFAULT_TPQN};
 use crate::multisync::MultiSyncState;
-use crate::multisync::{MultiSyncCommand, MultiSyncDisplay, MultiSyncEvent, Settings};
+use crate::multisync::{
+    MultiSyncCommand, MultiSyncDisplay, MultiSyncEvent, PortConfig, PortDisplay, PortInfo, Settings,
+};
+use crate::tempomap::TempoMap;
 use crossbeam_channel::{Receiver, Sender};
 use crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use ratatui::layout::Constraint;
@@ -17,9 +21,15 @@ use ratatui::{
 };
 use std::f64::consts::PI;
 use time::ext::NumericalDuration;
+use tracing::Level;
 use tui_big_text::{BigText, BigTextBuilder, PixelSize};
+use utils::circularbuffer::CircularBuffer;
+use utils::midimessages::MtcRate;
 use utils::programclock::{now, ProgramTime};
 
+const LOG_LINES: usize = 200; // Log lines kept for the log pane
+const LOG_HEIGHT: u16 = 8;
+
 pub struct MultiSyncUi {
     pub exit_requested: bool,
     cmd: Sender<MultiSyncCommand>,
@@ -28,6 +38,13 @@ pub struct MultiSyncUi {
     first_stop: Option<ProgramTime>,
     disp: MultiSyncDisplay,
     table_state: TableState,
+    bpm_input: Option<String>,
+    port_name_input: Option<String>,
+    song_input: Option<(Option<PortInfo>, String)>, // Song Select of a port or all ports
+    collapsed: Vec<String>,                         // Groups whose ports are hidden in the table
+    log_recv: Receiver<LogLine>,
+    log: CircularBuffer<LogLine>,
+    show_log: bool,
 }
 
 impl Widget for &mut MultiSyncUi {
@@ -38,23 +55,40 @@ impl Widget for &mut MultiSyncUi {
         let layout = Layout::vertical(vec![
             Constraint::Length(12),
             Constraint::Min(1),
+            Constraint::Length(if self.show_log { LOG_HEIGHT } else { 0 }),
             Constraint::Length(1),
         ]);
-        let areas: [Rect; 3] = layout.areas(area);
+        let areas: [Rect; 4] = layout.areas(area);
 
         CommonArea(&self.disp).render(areas[0], buf);
-        ClientArea(&self.disp, &mut self.table_state).render(areas[1], buf);
+        ClientArea(&self.disp, &self.collapsed, &mut self.table_state).render(areas[1], buf);
+        if self.show_log {
+            LogArea(&self.log).render(areas[2], buf);
+        }
         ExitConfirmation(self.first_exit, "Press Ctrl+C again to exit".to_owned())
             .render(area, buf);
         ExitConfirmation(self.first_stop, "Press Shift+Z again to stop".to_owned())
             .render(area, buf);
+        if let Some(input) = &self.bpm_input {
+            BpmInput(input, &self.disp.settings).render(area, buf);
+        }
+        if let Some(input) = &self.port_name_input {
+            PortNameInput(input).render(area, buf);
+        }
+        if let Some((port, input)) = &self.song_input {
+            SongInput(input, port.is_none()).render(area, buf);
+        }
     }
 }
 
 struct ExitConfirmation(Option<ProgramTime>, String);
+struct BpmInput<'a>(&'a str, &'a Settings);
+struct PortNameInput<'a>(&'a str);
+struct SongInput<'a>(&'a str, bool);
 struct CommonArea<'a>(&'a MultiSyncDisplay);
-struct ClientArea<'a>(&'a MultiSyncDisplay, &'a mut TableState);
+struct ClientArea<'a>(&'a MultiSyncDisplay, &'a [String], &'a mut TableState);
 struct BeatLine<'a>(&'a MultiSyncDisplay);
+struct LogArea<'a>(&'a CircularBuffer<LogLine>);
 
 impl Widget for ExitConfirmation {
     fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
@@ -69,24 +103,7 @@ impl Widget for ExitConfirmation {
             return;
         }
 
-        let popup_layout = Layout::default()
-            .direction(Direction::Vertical)
-            .constraints([
-                Constraint::Percentage((50) / 2),
-                Constraint::Length(5),
-                Constraint::Percentage((50) / 2),
-            ])
-            .split(area);
-
-        let parea = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([
-                Constraint::Percentage(40 / 2),
-                Constraint::Percentage(60),
-                Constraint::Percentage(40 / 2),
-            ])
-            .split(popup_layout[1])[1];
-
+        let parea = popup_area(area);
         let msg = Block::bordered()
             .padding(Padding::uniform(1))
             .style(Style::new().on_red().white())
@@ -104,6 +121,137 @@ impl Widget for ExitConfirmation {
     }
 }
 
+impl<'a> Widget for BpmInput<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let parea = popup_area(area);
+        let valid = self
+            .0
+            .parse::<f64>()
+            .is_ok_and(|bpm| bpm >= self.1.min_bpm && bpm <= self.1.max_bpm);
+
+        let msg = Block::bordered()
+            .padding(Padding::uniform(1))
+            .style(Style::new().on_blue().white())
+            .title(" Set BPM ".bold())
+            .title_bottom(format!(
+                " {}..{} BPM, (Enter) Apply, (Esc) Cancel ",
+                self.1.min_bpm, self.1.max_bpm
+            ));
+
+        let inner = msg.inner(parea);
+        Clear.render(parea, buf);
+        msg.render(parea, buf);
+
+        let text = Span::raw(format!("{}_", self.0)).bold();
+        let text = if valid { text.white() } else { text.red() };
+        Paragraph::new(text)
+            .alignment(Alignment::Center)
+            .render(inner, buf);
+    }
+}
+
+impl<'a> Widget for PortNameInput<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let parea = popup_area(area);
+        let msg = Block::bordered()
+            .padding(Padding::uniform(1))
+            .style(Style::new().on_blue().white())
+            .title(" New virtual port ".bold())
+            .title_bottom(" (Enter) Create, (Esc) Cancel ");
+
+        let inner = msg.inner(parea);
+        Clear.render(parea, buf);
+        msg.render(parea, buf);
+
+        Paragraph::new(Span::raw(format!("{}_", self.0)).bold().white())
+            .alignment(Alignment::Center)
+            .render(inner, buf);
+    }
+}
+
+impl<'a> Widget for SongInput<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let parea = popup_area(area);
+        let valid = self.0.is_empty() || parse_song(self.0).is_some();
+        let title = match self.1 {
+            true => " Song Select for all ports ",
+            false => " Song Select for the port ",
+        };
+        let msg = Block::bordered()
+            .padding(Padding::uniform(1))
+            .style(Style::new().on_blue().white())
+            .title(title.bold())
+            .title_bottom(" 0..127 or empty for none, (Enter) Apply, (Esc) Cancel ");
+
+        let inner = msg.inner(parea);
+        Clear.render(parea, buf);
+        msg.render(parea, buf);
+
+        let text = Span::raw(format!("{}_", self.0)).bold();
+        let text = if valid { text.white() } else { text.red() };
+        Paragraph::new(text)
+            .alignment(Alignment::Center)
+            .render(inner, buf);
+    }
+}
+
+fn parse_song(input: &str) -> Option<u8> {
+    input.parse::<u8>().ok().filter(|song| *song <= 127)
+}
+
+/// Line of the client table, ports of a group are listed below its header
+enum ClientRow<'a> {
+    Group(&'a str),
+    Port(&'a PortDisplay),
+}
+
+// Ports without a group first, then the groups in the order of the session file
+fn client_rows<'a>(disp: &'a MultiSyncDisplay, collapsed: &[String]) -> Vec<ClientRow<'a>> {
+    let members = |group: Option<&'a str>| {
+        disp.ports
+            .iter()
+            .filter(move |p| p.config.group.as_deref() == group)
+            .map(ClientRow::Port)
+    };
+    let mut rows: Vec<ClientRow> = members(None).collect();
+    for group in disp.groups.iter() {
+        rows.push(ClientRow::Group(group));
+        if !collapsed.contains(group) {
+            rows.extend(members(Some(group)));
+        }
+    }
+    rows
+}
+
+fn popup_area(area: Rect) -> Rect {
+    let popup_layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((50) / 2),
+            Constraint::Length(5),
+            Constraint::Percentage((50) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(40 / 2),
+            Constraint::Percentage(60),
+            Constraint::Percentage(40 / 2),
+        ])
+        .split(popup_layout[1])[1]
+}
+
 impl<'a> Widget for CommonArea<'a> {
     fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
     where
@@ -114,7 +262,7 @@ impl<'a> Widget for CommonArea<'a> {
             MultiSyncState::Stopped => {
                 block = block.title(" STOPPED ".slow_blink().red().bold());
                 block =
-                    block.title_bottom(" (Shift+s) Start, (Shift+z) Stop all, ([Shift] left/right) BPM, (</>) Quantum ")
+                    block.title_bottom(" (Shift+s) Start, (Shift+z) Stop all, ([Shift/Ctrl/Alt] left/right) BPM, (b) Enter BPM, (</>) Quantum, (g) Song ")
             }
             MultiSyncState::Started(_) => {
                 block = block.title(" RUNNING ".green().bold());
@@ -132,7 +280,7 @@ impl<'a> Widget for CommonArea<'a> {
         let inner_text = vec![
             Line::from(vec![
                 Span::styled(
-                    format!("{:>5.1} ", self.0.settings.bpm),
+                    format!("{:>6.2} ", self.0.settings.bpm),
                     Style::new().white().bold(),
                 ),
                 Span::raw("BPM    "),
@@ -151,12 +299,27 @@ impl<'a> Widget for CommonArea<'a> {
                     }
                     _ => Span::styled("00:00:00.0000", Style::new().slow_blink()),
                 },
+                Span::raw(format!(
+                    "    late {:.2}/{:.2} ms",
+                    self.0.lateness.mean.as_secs_f64() * 1000.0,
+                    self.0.lateness.max.as_secs_f64() * 1000.0
+                ))
+                .dim(),
+                if self.0.recording {
+                    Span::raw("    REC (w) Save").red()
+                } else {
+                    Span::raw("")
+                },
+            ]),
+            song_line(self.0),
+            Line::from(vec![
+                Span::raw(format!(
+                    "Quantum {:2}  {}",
+                    self.0.settings.quantum, self.0.settings.time_signature
+                )),
+                Span::raw("    "),
+                scene_span(self.0),
             ]),
-            Line::from(vec![]),
-            Line::from(vec![Span::raw(format!(
-                "Quantum {:2}",
-                self.0.settings.quantum
-            ))]),
         ];
         let ip = Paragraph::new(inner_text);
         let beatline = BeatLine(&self.0);
@@ -165,6 +328,80 @@ impl<'a> Widget for CommonArea<'a> {
     }
 }
 
+fn song_line(disp: &MultiSyncDisplay) -> Line<'_> {
+    if let (Some(position), Some(map)) = (disp.position, &disp.tempo_map) {
+        return tempo_map_line(disp, map, position);
+    }
+    if disp.songs.is_empty() {
+        return Line::from(vec![]);
+    }
+    let current = match disp.song.and_then(|i| disp.songs.get(i).map(|s| (i, s))) {
+        Some((i, song)) => format!("Song {}/{} {}", i + 1, disp.songs.len(), song),
+        None => "Song -".to_owned(),
+    };
+    let next = match &disp.pending_song {
+        Some((i, time)) => Span::styled(
+            format!(
+                "-> {} in {:.0} beats",
+                disp.songs[*i],
+                disp.settings.beats_until(*time, None).ceil()
+            ),
+            Style::new().yellow(),
+        ),
+        None => {
+            let next = disp.song.map(|i| i + 1).unwrap_or(0);
+            match disp.songs.get(next) {
+                Some(song) => Span::raw(format!("Next: {} (n/p) Arm", song)).dim(),
+                None => Span::raw("(n/p) Arm").dim(),
+            }
+        }
+    };
+    Line::from(vec![Span::raw(current), Span::raw("    "), next])
+}
+
+fn tempo_map_line(disp: &MultiSyncDisplay, map: &TempoMap, position: f64) -> Line<'static> {
+    let current = match disp.state {
+        MultiSyncState::Started(_) => disp.settings.get_quarter(disp.grid, None),
+        MultiSyncState::Stopped => position,
+    };
+    let bar = Span::raw(format!(
+        "Tempo map  Bar {}",
+        map.bar_at(current).floor() + 1.0
+    ));
+    let next = match (&disp.state, disp.pending_tempo) {
+        (MultiSyncState::Started(_), Some((bpm, time))) => Span::styled(
+            format!(
+                "-> {:.2} BPM in {:.0} beats",
+                bpm,
+                disp.settings.beats_until(time, None).ceil()
+            ),
+            Style::new().yellow(),
+        ),
+        (MultiSyncState::Started(_), None) => Span::raw(""),
+        (MultiSyncState::Stopped, _) => Span::raw("([/]) Locate, (Home) Rewind").dim(),
+    };
+    Line::from(vec![bar, Span::raw("    "), next])
+}
+
+fn scene_span(disp: &MultiSyncDisplay) -> Span<'_> {
+    if disp.scenes.is_empty() {
+        return Span::raw("");
+    }
+    let current = disp.scene.as_deref().unwrap_or("-");
+    match &disp.pending_scene {
+        Some((next, time)) => Span::styled(
+            format!(
+                "Scene {} -> {} in {:.0} beats",
+                current,
+                next,
+                disp.settings.beats_until(*time, None).ceil()
+            ),
+            Style::new().yellow(),
+        ),
+        None => Span::raw(format!("Scene {} (1-9) Recall", current)),
+    }
+}
+
 impl<'a> Widget for ClientArea<'a> {
     fn render(mut self, area: Rect, buf: &mut ratatui::prelude::Buffer)
     where
@@ -173,62 +410,266 @@ impl<'a> Widget for ClientArea<'a> {
         let block = Block::bordered()
             .padding(Padding::uniform(1))
             .title(" Clients ")
-            .title_bottom(" (Up/Down) Select, (Enter) Add/Start, (z) Stop, (Del) Remove ");
+            .title_bottom(
+                " (Up/Down) Select, (Enter) Add/Start, (z) Stop, (Space) Fold group, (Del) Remove, (x) Reset, (m) Sync, (t) PPQN, (r) Rate, (q) Quantum, (c) Cue, (s) Song, (h) Idle ",
+            );
 
         let inner = block.inner(area);
         block.render(area, buf);
 
-        let rows: Vec<Row> = self
-            .0
-            .ports
-            .iter()
-            .map(|port| {
-                Row::new(vec![{
-                    let style = match port.state {
-                        Some(MidiSyncState::Running) => Style::new().green(),
-                        Some(MidiSyncState::Stopped) => Style::new().white(),
-                        Some(MidiSyncState::Starting) => Style::new().yellow().dim().slow_blink(),
-                        _ => Style::default(),
+        let global_tpqn = self.0.settings.tpqn.unwrap_or(DEFAULT_TPQN);
+        let port_row = |port: &PortDisplay| {
+            let style = match port.state {
+                Some(MidiSyncState::Running) => Style::new().green(),
+                Some(MidiSyncState::Stopped) => Style::new().white(),
+                Some(MidiSyncState::Starting) => Style::new().yellow().dim().slow_blink(),
+                Some(MidiSyncState::Error(_)) => Style::new().red(),
+                None => Style::default(),
+            };
+            let label = match port.info.is_virtual() {
+                true => format!("{} (virtual)", port.info.name),
+                false => port.info.name.to_owned(),
+            };
+            // Members are indented below their group header
+            let label = match port.config.group {
+                Some(_) => format!("  {}", label),
+                None => label,
+            };
+            let name = match (&port.state, port.recovery) {
+                (Some(MidiSyncState::Error(e)), Some(recovery)) => format!(
+                    "{}  {} (retry {} in {:.0}s)",
+                    label,
+                    e,
+                    recovery.attempts + 1,
+                    (recovery.next_attempt.0.saturating_sub(now().0)).as_secs_f64()
+                ),
+                (Some(MidiSyncState::Error(e)), None) => format!("{}  {}", label, e),
+                _ => label,
+            };
+
+            let tpqn = match port.config.tpqn {
+                Some(tpqn) => Cell::new(format!("{}", tpqn)),
+                None => Cell::new(format!("{}", global_tpqn)).dim(),
+            };
+            let rate = Cell::new(port.config.rate.to_string());
+            let rate = if port.config.rate == Rate::default() {
+                rate.dim()
+            } else {
+                rate
+            };
+            let quantum = match port.config.quantum {
+                Some(quantum) => Cell::new(format!("{}", quantum)),
+                None => Cell::new(format!("{}", self.0.settings.quantum)).dim(),
+            };
+            let countdown = match (&port.state, port.start_time) {
+                (Some(MidiSyncState::Starting), Some(start_time)) => {
+                    let signature = self.0.settings.time_signature;
+                    let beats = (self.0.settings.beats_until(start_time, None)
+                        / signature.beat_length())
+                    .ceil() as u64;
+                    let num = signature.num as u64;
+                    Cell::new(format!("{:>3}.{}", beats / num, beats % num))
+                }
+                _ => Cell::new(""),
+            };
+            // Tick lateness in ms, only meaningful while running
+            let ms =
+                |d: std::time::Duration| Cell::new(format!("{:>6.2}", d.as_secs_f64() * 1000.0));
+            let lateness = match (&port.state, port.metrics.map(|m| m.lateness)) {
+                (Some(MidiSyncState::Running), Some(l)) => {
+                    vec![ms(l.mean), ms(l.p99), ms(l.max), ms(l.jitter)]
+                }
+                _ => vec![Cell::new(""); 4],
+            };
+            let mode = match port.config.mode {
+                SyncMode::Clock => Cell::new("Clock").dim(),
+                SyncMode::Timecode(rate) => Cell::new(format!("MTC {}", rate)),
+            };
+            let cue = match port.config.cue.map(|c| c.scope) {
+                Some(CueScope::Port) => "own",
+                Some(CueScope::Global) => "all",
+                None => "",
+            };
+            let song = match (port.config.song_select, self.0.settings.song_select) {
+                (Some(song), _) => Cell::new(format!("{}", song)),
+                (None, Some(song)) => Cell::new(format!("{}", song)).dim(),
+                (None, None) => Cell::new(""),
+            };
+            let idle = match port.config.heartbeat {
+                Heartbeat::Off => "",
+                Heartbeat::Sensing => "sense",
+                Heartbeat::Clock => "clock",
+            };
+            let mut cells = vec![
+                Cell::new(name),
+                mode,
+                tpqn,
+                rate,
+                quantum,
+                countdown,
+                Cell::new(cue),
+                song,
+                Cell::new(idle),
+            ];
+            cells.extend(lateness);
+            Row::new(cells).style(style)
+        };
+        let rows: Vec<Row> = client_rows(self.0, self.1)
+            .into_iter()
+            .map(|row| match row {
+                ClientRow::Port(port) => port_row(port),
+                ClientRow::Group(group) => {
+                    let members = self
+                        .0
+                        .ports
+                        .iter()
+                        .filter(|p| p.config.group.as_deref() == Some(group));
+                    let (total, running) = members.fold((0, 0), |(total, running), p| {
+                        let is_running = matches!(p.state, Some(MidiSyncState::Running));
+                        (total + 1, running + is_running as usize)
+                    });
+                    let fold = match self.1.iter().any(|g| g == group) {
+                        true => "▸",
+                        false => "▾",
                     };
-                    Cell::new(port.info.name.to_owned()).style(style)
-                }])
+                    let header = format!("{} {}  {}/{} running", fold, group, running, total);
+                    Row::new(vec![Cell::new(header)]).bold().cyan()
+                }
             })
             .collect();
 
-        let clients = Table::new(rows, [Constraint::Min(40)])
-            .highlight_style(Style::new().reversed())
-            // ...and potentially show a symbol in front of the selection.
-            .highlight_symbol(" >> ");
-        let table_state: &mut TableState = &mut self.1;
+        let clients = Table::new(
+            rows,
+            [
+                Constraint::Min(40),
+                Constraint::Length(11),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(8),
+                Constraint::Length(9),
+                Constraint::Length(4),
+                Constraint::Length(5),
+                Constraint::Length(6),
+                Constraint::Length(7),
+                Constraint::Length(7),
+                Constraint::Length(7),
+                Constraint::Length(7),
+            ],
+        )
+        .header(
+            Row::new(vec![
+                "Port",
+                "Sync",
+                "PPQN",
+                "Rate",
+                "Quantum",
+                "Starts in",
+                "Cue",
+                "Song",
+                "Idle",
+                "Late ms",
+                "p99",
+                "Max",
+                "Jitter",
+            ])
+            .bold(),
+        )
+        .highlight_style(Style::new().reversed())
+        // ...and potentially show a symbol in front of the selection.
+        .highlight_symbol(" >> ");
+        let table_state: &mut TableState = &mut self.2;
         StatefulWidget::render(clients, inner, buf, table_state);
     }
 }
 
+impl<'a> Widget for LogArea<'a> {
+    fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
+    where
+        Self: Sized,
+    {
+        let block = Block::bordered()
+            .title(" Log ")
+            .title_bottom(" (l) Show/Hide ");
+        let inner = block.inner(area);
+        block.render(area, buf);
+
+        // Newest lines at the bottom
+        let lines: Vec<Line> = self
+            .0
+            .get_buf()
+            .iter()
+            .rev()
+            .take(inner.height as usize)
+            .rev()
+            .map(|line| {
+                let style = match line.level {
+                    Level::ERROR => Style::new().red(),
+                    Level::WARN => Style::new().yellow(),
+                    _ => Style::new(),
+                };
+                Line::from(vec![
+                    Span::raw(format!("{:>10.3} ", line.time.0.as_secs_f64())).dim(),
+                    Span::styled(format!("{:<5} ", line.level), style.bold()),
+                    Span::styled(line.message.clone(), style),
+                ])
+            })
+            .collect();
+        Paragraph::new(lines).render(inner, buf);
+    }
+}
+
 impl<'a> Widget for BeatLine<'a> {
     fn render(self, area: Rect, buf: &mut ratatui::prelude::Buffer)
     where
         Self: Sized,
     {
         let t = now();
-        let (intensity, last_4, fill, mfill) = if let MultiSyncState::Started(ts) = self.0.state {
-            let quarter = self.0.settings.get_quarter(ts, Some(t));
-            let partial = quarter.fract();
+        let signature = self.0.settings.time_signature;
+        // Count in the last bar before the next port starts
+        let count_in = self
+            .0
+            .ports
+            .iter()
+            .filter(|p| matches!(p.state, Some(MidiSyncState::Starting)))
+            .filter_map(|p| p.start_time)
+            .map(|start_time| {
+                (self.0.settings.beats_until(start_time, Some(t)) / signature.beat_length()).ceil()
+                    as u8
+            })
+            .filter(|beats| *beats > 0)
+            .min()
+            .filter(|beats| *beats <= signature.num);
+        let (intensity, beat_in_bar, fill, mfill) = if let MultiSyncState::Started(_) = self.0.state
+        {
+            let quarter = self.0.settings.get_quarter(self.0.grid, Some(t));
+            let beat = quarter / signature.beat_length();
+            let partial = beat.fract();
             let beat_prog = partial / 0.5;
             let intensity = if beat_prog < 1.0 {
                 (PI / 2.0 * beat_prog).cos().powf(0.5)
             } else {
                 0.0
             };
-            let last_4 = (quarter.floor() % 4.0) as u8;
-            let bars_completed = (quarter / 4.0).floor();
-            let total_bars_in_quantum = (self.0.settings.quantum / 4.0).ceil();
+            // Bars follow the time signature changes of a tempo map
+            let (beat_in_bar, bars_completed) = match &self.0.tempo_map {
+                Some(map) => {
+                    let bar = map.bar_at(quarter);
+                    let beat_in_bar = (bar.fract() * signature.num as f64 + 1e-9).floor();
+                    (beat_in_bar as u8, bar.floor())
+                }
+                None => (
+                    (beat.floor() % signature.num as f64) as u8,
+                    (quarter / signature.bar_length()).floor(),
+                ),
+            };
+            let total_bars_in_quantum = (self.0.settings.quantum / signature.bar_length()).ceil();
             let bars_in_quantum_completed = bars_completed % total_bars_in_quantum;
             let fill = (16.0 * bars_in_quantum_completed / total_bars_in_quantum) as u32;
             let mut mfill = (16.0 / total_bars_in_quantum) as u32;
             if fill + mfill == 15 && mfill > 1 {
                 mfill += 1;
             }
-            ((255.0 * intensity) as u8, last_4 + 1, fill, mfill)
+            ((255.0 * intensity) as u8, beat_in_bar + 1, fill, mfill)
         } else {
             (0, 1, 0, 0)
         };
@@ -236,7 +677,10 @@ impl<'a> Widget for BeatLine<'a> {
             .pixel_size(PixelSize::Quadrant)
             .lines(vec![Line::from(vec![
                 Span::styled(" ", Style::new().bg(Color::Rgb(intensity, 0, 0))),
-                Span::raw(format!(" {} ", last_4)),
+                match count_in {
+                    Some(beats) => Span::styled(format!(" {} ", beats), Style::new().yellow()),
+                    None => Span::raw(format!(" {} ", beat_in_bar)),
+                },
                 Span::styled(
                     (0..fill).map(|_| " ").collect::<String>(),
                     Style::new().bg(Color::Rgb(255, 255, 255)),
@@ -257,7 +701,11 @@ impl<'a> Widget for BeatLine<'a> {
 }
 
 impl MultiSyncUi {
-    pub fn new(cmd: Sender<MultiSyncCommand>, recv: Receiver<MultiSyncEvent>) -> MultiSyncUi {
+    pub fn new(
+        cmd: Sender<MultiSyncCommand>,
+        recv: Receiver<MultiSyncEvent>,
+        log_recv: Receiver<LogLine>,
+    ) -> MultiSyncUi {
         MultiSyncUi {
             exit_requested: false,
             first_exit: None,
@@ -266,16 +714,38 @@ impl MultiSyncUi {
             recv,
             disp: MultiSyncDisplay::default(),
             table_state: TableState::default().with_selected(Some(0)),
+            bpm_input: None,
+            port_name_input: None,
+            song_input: None,
+            collapsed: Vec::new(),
+            log_recv,
+            log: CircularBuffer::new(LOG_LINES),
+            show_log: true,
         }
     }
     pub fn update(&mut self) {
         self.process_key_events();
         self.process_sync_events();
+        while let Ok(line) = self.log_recv.try_recv() {
+            self.log.add(line);
+        }
     }
 
     fn process_key_events(&mut self) {
         if event::poll(std::time::Duration::from_millis(16)).unwrap() {
             if let event::Event::Key(key) = event::read().unwrap() {
+                if self.bpm_input.is_some() {
+                    self.input_bpm(key);
+                    return;
+                }
+                if self.port_name_input.is_some() {
+                    self.input_port_name(key);
+                    return;
+                }
+                if self.song_input.is_some() {
+                    self.input_song(key);
+                    return;
+                }
                 match (key.kind, key.code, key.modifiers) {
                     (KeyEventKind::Press, KeyCode::Char('c'), KeyModifiers::CONTROL) => {
                         self.exit_requested = self.request_quit();
@@ -283,10 +753,16 @@ impl MultiSyncUi {
                     (
                         KeyEventKind::Press | KeyEventKind::Repeat,
                         KeyCode::Left | KeyCode::Right,
-                        KeyModifiers::NONE | KeyModifiers::SHIFT,
+                        KeyModifiers::NONE
+                        | KeyModifiers::SHIFT
+                        | KeyModifiers::CONTROL
+                        | KeyModifiers::ALT,
                     ) => {
                         self.control_bpm(key);
                     }
+                    (KeyEventKind::Press, KeyCode::Char('b'), KeyModifiers::NONE) => {
+                        self.open_bpm_input();
+                    }
                     (
                         KeyEventKind::Press | KeyEventKind::Repeat,
                         KeyCode::Up | KeyCode::Down,
@@ -315,6 +791,66 @@ impl MultiSyncUi {
                     (KeyEventKind::Press, KeyCode::Delete, KeyModifiers::NONE) => {
                         self.remove_port();
                     }
+                    (KeyEventKind::Press, KeyCode::Char('t'), KeyModifiers::NONE) => {
+                        self.cycle_port_tpqn();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('m'), KeyModifiers::NONE) => {
+                        self.cycle_port_mode();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('h'), KeyModifiers::NONE) => {
+                        self.cycle_port_heartbeat();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('r'), KeyModifiers::NONE) => {
+                        self.cycle_port_rate();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('q'), KeyModifiers::NONE) => {
+                        self.cycle_port_quantum();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('c'), KeyModifiers::NONE) => {
+                        self.cycle_port_cue();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char(c @ '1'..='9'), KeyModifiers::NONE) => {
+                        self.recall_scene(c);
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('n'), KeyModifiers::NONE) => {
+                        self.arm_song(true);
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('p'), KeyModifiers::NONE) => {
+                        self.arm_song(false);
+                    }
+                    (
+                        KeyEventKind::Press | KeyEventKind::Repeat,
+                        KeyCode::Char('[') | KeyCode::Char(']'),
+                        KeyModifiers::NONE,
+                    ) => {
+                        self.locate(key.code == KeyCode::Char(']'));
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('w'), KeyModifiers::NONE)
+                        if self.disp.recording =>
+                    {
+                        self.cmd.send(MultiSyncCommand::SaveRecording).unwrap();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('x'), KeyModifiers::NONE) => {
+                        self.reset_port();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('v'), KeyModifiers::NONE) => {
+                        self.port_name_input = Some(String::new());
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('s'), KeyModifiers::NONE) => {
+                        self.open_song_input(true);
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('g'), KeyModifiers::NONE) => {
+                        self.open_song_input(false);
+                    }
+                    (KeyEventKind::Press, KeyCode::Char(' '), KeyModifiers::NONE) => {
+                        self.toggle_group();
+                    }
+                    (KeyEventKind::Press, KeyCode::Char('l'), KeyModifiers::NONE) => {
+                        self.show_log = !self.show_log;
+                    }
+                    (KeyEventKind::Press, KeyCode::Home, KeyModifiers::NONE) => {
+                        self.cmd.send(MultiSyncCommand::Locate(0.0)).unwrap();
+                    }
                     _ => (),
                 }
             }
@@ -325,7 +861,7 @@ impl MultiSyncUi {
         while let Ok(msg) = self.recv.try_recv() {
             match msg {
                 MultiSyncEvent::DisplayUpdate(disp) => {
-                    self.disp = disp;
+                    self.disp = *disp;
                     self.post_update_checks();
                 }
                 _ => (),
@@ -355,42 +891,174 @@ impl MultiSyncUi {
         let amt = match key.modifiers {
             KeyModifiers::NONE => 1.0,
             KeyModifiers::SHIFT => 10.0,
+            KeyModifiers::CONTROL => 0.1,
+            KeyModifiers::ALT => 0.01,
             _ => 0.0,
         };
 
         let total: f64 = dir * amt;
-        if total.abs() > 0.01 {
-            self.cmd
-                .send(MultiSyncCommand::UpdateSettings(Settings {
-                    bpm: self.disp.settings.bpm + total,
-                    ..self.disp.settings
-                }))
-                .unwrap();
+        if total.abs() > 0.001 {
+            self.set_bpm(self.disp.settings.clamp_bpm(self.disp.settings.bpm + total));
+        }
+    }
+
+    fn set_bpm(&mut self, bpm: f64) {
+        self.cmd
+            .send(MultiSyncCommand::UpdateSettings(Settings {
+                bpm,
+                ..self.disp.settings.clone()
+            }))
+            .unwrap();
+    }
+
+    fn open_bpm_input(&mut self) {
+        // Tempo can only be changed while stopped
+        if let MultiSyncState::Stopped = self.disp.state {
+            self.bpm_input = Some(String::new());
+        }
+    }
+
+    fn input_bpm(&mut self, key: KeyEvent) {
+        if key.kind == KeyEventKind::Release {
+            return;
+        }
+        let Some(input) = self.bpm_input.as_mut() else {
+            return;
+        };
+        match key.code {
+            KeyCode::Char(c) if c.is_ascii_digit() && input.len() < 7 => input.push(c),
+            KeyCode::Char('.') | KeyCode::Char(',') if !input.contains('.') => input.push('.'),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => self.bpm_input = None,
+            KeyCode::Enter => {
+                let settings = &self.disp.settings;
+                if let Ok(bpm) = input.parse::<f64>() {
+                    if bpm >= settings.min_bpm && bpm <= settings.max_bpm {
+                        self.bpm_input = None;
+                        self.set_bpm(settings.clamp_bpm(bpm));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn input_port_name(&mut self, key: KeyEvent) {
+        if key.kind == KeyEventKind::Release {
+            return;
+        }
+        let Some(input) = self.port_name_input.as_mut() else {
+            return;
+        };
+        match key.code {
+            KeyCode::Char(c) if !c.is_control() && input.len() < 32 => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => self.port_name_input = None,
+            KeyCode::Enter if !input.trim().is_empty() => {
+                self.cmd
+                    .send(MultiSyncCommand::AddVirtualPort(input.trim().to_owned()))
+                    .unwrap();
+                self.port_name_input = None;
+            }
+            _ => (),
+        }
+    }
+
+    fn open_song_input(&mut self, port: bool) {
+        match port {
+            true => {
+                if let Some(port) = self.selected_port() {
+                    let input = port.config.song_select.map(|s| s.to_string());
+                    self.song_input = Some((Some(port.info.clone()), input.unwrap_or_default()));
+                }
+            }
+            // Global settings can only be changed while stopped
+            false => {
+                if let MultiSyncState::Stopped = self.disp.state {
+                    let input = self.disp.settings.song_select.map(|s| s.to_string());
+                    self.song_input = Some((None, input.unwrap_or_default()));
+                }
+            }
+        }
+    }
+
+    fn input_song(&mut self, key: KeyEvent) {
+        if key.kind == KeyEventKind::Release {
+            return;
+        }
+        let Some((port, input)) = self.song_input.as_mut() else {
+            return;
+        };
+        match key.code {
+            KeyCode::Char(c) if c.is_ascii_digit() && input.len() < 3 => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => self.song_input = None,
+            KeyCode::Enter if input.is_empty() || parse_song(input).is_some() => {
+                let song = parse_song(input);
+                match port.take() {
+                    Some(port) => {
+                        let config =
+                            self.disp
+                                .ports
+                                .iter()
+                                .find(|p| p.info == port)
+                                .map(|p| PortConfig {
+                                    song_select: song,
+                                    ..p.config.clone()
+                                });
+                        if let Some(config) = config {
+                            self.cmd
+                                .send(MultiSyncCommand::UpdatePortConfig(port, config))
+                                .unwrap();
+                        }
+                    }
+                    None => self
+                        .cmd
+                        .send(MultiSyncCommand::UpdateSettings(Settings {
+                            song_select: song,
+                            ..self.disp.settings.clone()
+                        }))
+                        .unwrap(),
+                }
+                self.song_input = None;
+            }
+            _ => (),
         }
     }
 
     fn control_quantum(&mut self, inc: bool) {
+        let bar = self.disp.settings.time_signature.bar_length();
         let nc = if inc {
-            ((self.disp.settings.quantum / 4.0).floor() + 1.0) * 4.0
+            ((self.disp.settings.quantum / bar).floor() + 1.0) * bar
         } else {
-            ((self.disp.settings.quantum / 4.0).floor() - 1.0) * 4.0
+            ((self.disp.settings.quantum / bar).floor() - 1.0) * bar
         };
 
         self.cmd
             .send(MultiSyncCommand::UpdateSettings(Settings {
                 quantum: nc,
-                ..self.disp.settings
+                ..self.disp.settings.clone()
             }))
             .unwrap();
     }
 
     fn navigate(&mut self, key: KeyEvent) {
+        let rows = client_rows(&self.disp, &self.collapsed).len();
+        if rows == 0 {
+            return;
+        }
         let newidx = match (key.code, self.table_state.selected()) {
             (_, None) => Some(0),
-            (KeyCode::Up, Some(0)) => Some(self.disp.ports.len() - 1),
+            (KeyCode::Up, Some(0)) => Some(rows - 1),
             (KeyCode::Up, Some(i)) => Some(i - 1),
             (KeyCode::Down, Some(i)) => {
-                if i == self.disp.ports.len() - 1 {
+                if i >= rows - 1 {
                     Some(0)
                 } else {
                     Some(i + 1)
@@ -403,10 +1071,11 @@ impl MultiSyncUi {
     }
 
     fn post_update_checks(&mut self) {
+        let rows = client_rows(&self.disp, &self.collapsed).len();
         let new_state = match self.table_state.selected() {
             Some(i) => {
-                if i >= self.disp.ports.len() && self.disp.ports.len() > 0 {
-                    Some(self.disp.ports.len() - 1)
+                if i >= rows && rows > 0 {
+                    Some(rows - 1)
                 } else {
                     Some(i)
                 }
@@ -418,45 +1087,185 @@ impl MultiSyncUi {
     }
 
     fn act_on_port(&mut self) {
-        if let Some(idx) = self.table_state.selected() {
-            if let Some(port) = self.disp.ports.get(idx) {
-                match port.state {
-                    None => {
-                        self.cmd
-                            .send(MultiSyncCommand::AddSyncForPort(port.info.clone()))
-                            .unwrap();
-                    }
-                    Some(MidiSyncState::Stopped) => {
-                        self.cmd
-                            .send(MultiSyncCommand::StartPort(port.info.clone()))
-                            .unwrap();
-                    }
-                    _ => (),
+        if let Some(group) = self.selected_group() {
+            self.cmd.send(MultiSyncCommand::StartGroup(group)).unwrap();
+            return;
+        }
+        if let Some(port) = self.selected_port() {
+            match port.state {
+                None => {
+                    self.cmd
+                        .send(MultiSyncCommand::AddSyncForPort(port.info.clone()))
+                        .unwrap();
+                }
+                Some(MidiSyncState::Stopped) => {
+                    self.cmd
+                        .send(MultiSyncCommand::StartPort(port.info.clone()))
+                        .unwrap();
                 }
+                _ => (),
             }
         }
     }
 
     fn stop_port(&mut self) {
-        if let Some(idx) = self.table_state.selected() {
-            if let Some(port) = self.disp.ports.get(idx) {
-                self.cmd
-                    .send(MultiSyncCommand::StopPort(port.info.clone()))
-                    .unwrap();
-            }
+        if let Some(group) = self.selected_group() {
+            self.cmd.send(MultiSyncCommand::StopGroup(group)).unwrap();
+            return;
+        }
+        if let Some(port) = self.selected_port() {
+            self.cmd
+                .send(MultiSyncCommand::StopPort(port.info.clone()))
+                .unwrap();
         }
     }
 
     fn remove_port(&mut self) {
-        if let Some(idx) = self.table_state.selected() {
-            if let Some(port) = self.disp.ports.get(idx) {
+        if let Some(port) = self.selected_port() {
+            self.cmd
+                .send(MultiSyncCommand::DelSyncForPort(port.info.clone()))
+                .unwrap();
+        }
+    }
+
+    fn toggle_group(&mut self) {
+        if let Some(group) = self.selected_group() {
+            match self.collapsed.iter().position(|g| *g == group) {
+                Some(idx) => {
+                    self.collapsed.remove(idx);
+                }
+                None => self.collapsed.push(group),
+            }
+        }
+    }
+
+    fn reset_port(&mut self) {
+        if let Some(port) = self.selected_port() {
+            if port.state.is_some() {
                 self.cmd
-                    .send(MultiSyncCommand::DelSyncForPort(port.info.clone()))
+                    .send(MultiSyncCommand::ResetPort(port.info.clone()))
                     .unwrap();
             }
         }
     }
 
+    fn selected_port(&self) -> Option<&PortDisplay> {
+        let idx = self.table_state.selected()?;
+        match client_rows(&self.disp, &self.collapsed).get(idx)? {
+            ClientRow::Port(port) => Some(port),
+            ClientRow::Group(_) => None,
+        }
+    }
+
+    fn selected_group(&self) -> Option<String> {
+        let idx = self.table_state.selected()?;
+        match client_rows(&self.disp, &self.collapsed).get(idx)? {
+            ClientRow::Group(group) => Some(group.to_string()),
+            ClientRow::Port(_) => None,
+        }
+    }
+
+    fn update_port_config(&self, f: impl FnOnce(&mut PortConfig)) {
+        if let Some(port) = self.selected_port() {
+            let mut config = port.config.clone();
+            f(&mut config);
+            self.cmd
+                .send(MultiSyncCommand::UpdatePortConfig(
+                    port.info.clone(),
+                    config,
+                ))
+                .unwrap();
+        }
+    }
+
+    fn cycle_port_tpqn(&mut self) {
+        const CHOICES: [Option<f64>; 4] = [None, Some(4.0), Some(24.0), Some(48.0)];
+        self.update_port_config(|config| config.tpqn = next_choice(&CHOICES, &config.tpqn));
+    }
+
+    fn cycle_port_rate(&mut self) {
+        let choices = [
+            Rate::new(1, 1),
+            Rate::new(1, 2),
+            Rate::new(2, 1),
+            Rate::new(1, 4),
+            Rate::new(4, 1),
+        ];
+        self.update_port_config(|config| config.rate = next_choice(&choices, &config.rate));
+    }
+
+    fn cycle_port_mode(&mut self) {
+        let choices = [
+            SyncMode::Clock,
+            SyncMode::Timecode(MtcRate::Fps24),
+            SyncMode::Timecode(MtcRate::Fps25),
+            SyncMode::Timecode(MtcRate::Fps30Drop),
+            SyncMode::Timecode(MtcRate::Fps30),
+        ];
+        self.update_port_config(|config| config.mode = next_choice(&choices, &config.mode));
+    }
+
+    fn cycle_port_heartbeat(&mut self) {
+        const CHOICES: [Heartbeat; 3] = [Heartbeat::Off, Heartbeat::Sensing, Heartbeat::Clock];
+        self.update_port_config(|config| {
+            config.heartbeat = next_choice(&CHOICES, &config.heartbeat)
+        });
+    }
+
+    fn cycle_port_quantum(&mut self) {
+        const CHOICES: [Option<f64>; 6] = [
+            None,
+            Some(4.0),
+            Some(8.0),
+            Some(16.0),
+            Some(32.0),
+            Some(64.0),
+        ];
+        self.update_port_config(|config| config.quantum = next_choice(&CHOICES, &config.quantum));
+    }
+
+    fn cycle_port_cue(&mut self) {
+        self.update_port_config(|config| {
+            let click = config.click;
+            let choices = [
+                None,
+                Some(click.cue(CueScope::Port)),
+                Some(click.cue(CueScope::Global)),
+            ];
+            config.cue = next_choice(&choices, &config.cue)
+        });
+    }
+
+    fn recall_scene(&mut self, key: char) {
+        let idx = key.to_digit(10).unwrap_or(0) as usize;
+        if let Some(scene) = idx.checked_sub(1).and_then(|i| self.disp.scenes.get(i)) {
+            self.cmd
+                .send(MultiSyncCommand::RecallScene(scene.clone()))
+                .unwrap();
+        }
+    }
+
+    fn arm_song(&mut self, next: bool) {
+        let idx = match (self.disp.song, next) {
+            (None, _) => Some(0),
+            (Some(i), true) => Some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+        };
+        if let Some(idx) = idx.filter(|i| *i < self.disp.songs.len()) {
+            self.cmd.send(MultiSyncCommand::ArmSong(idx)).unwrap();
+        }
+    }
+
+    /// Move the tempo map position by one bar
+    fn locate(&mut self, forward: bool) {
+        if let (Some(position), Some(map)) = (self.disp.position, &self.disp.tempo_map) {
+            let bar = map.bar_at(position).round() + if forward { 1.0 } else { -1.0 };
+            self.cmd
+                .send(MultiSyncCommand::Locate(map.beat_of_bar(bar.max(0.0))))
+                .unwrap();
+        }
+    }
+
     fn start_all(&mut self) {
         self.cmd.send(MultiSyncCommand::Start).unwrap();
     }
@@ -477,3 +1286,12 @@ impl MultiSyncUi {
         self.first_stop = Some(now());
     }
 }
+
+fn next_choice<T: PartialEq + Clone>(choices: &[T], current: &T) -> T {
+    let idx = choices
+        .iter()
+        .position(|c| c == current)
+        .map(|i| (i + 1) % choices.len())
+        .unwrap_or(0);
+    choices[idx].clone()
+}
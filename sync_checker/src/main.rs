@@ -46,6 +46,7 @@ pub struct MidiSyncClient {
     rx: Receiver<TimedMessage>,
     midi_client: MidiInputConnection<()>,
     last_rcv: Option<DoubleTime>,
+    last_sensing: Option<ProgramTime>,
     history: CircularBuffer<Duration>,
     tpqn: f64,
     state: ClientState,
@@ -59,6 +60,7 @@ pub struct MidiSyncDisplay {
     bpm_overall: f64,
     bpm_recent: f64,
     has_clock: bool,
+    has_sensing: bool,
     total_quarters: f64,
 }
 
@@ -82,6 +84,7 @@ impl MidiSyncClient {
             rx,
             midi_client,
             last_rcv: None,
+            last_sensing: None,
             history: CircularBuffer::new(history_size),
             tpqn,
             state: ClientState::Stopped,
@@ -93,6 +96,12 @@ impl MidiSyncClient {
     pub fn run(&mut self) -> Result<()> {
         while let Ok((time, msg)) = self.rx.try_recv() {
             match (&self.state, msg) {
+                (_, MidiRealtimeMessage::ActiveSensing(_)) => {
+                    if self.last_sensing.is_none() {
+                        info!("Active Sensing detected");
+                    }
+                    self.last_sensing = Some(time);
+                }
                 (ClientState::Started(Some(_)), MidiRealtimeMessage::MidiClock(t)) => {
                     self.last_rcv = Some(DoubleTime(time, t));
                     self.history.add(t.clone());
@@ -109,6 +118,12 @@ impl MidiSyncClient {
                     self.history.clear();
                     self.total_ticks = 0.;
                 }
+                (ClientState::Stopped, MidiRealtimeMessage::MidiContinue(t)) => {
+                    // Counting goes on from where it stopped
+                    info!(time = ?DoubleTime(time, t), "Continuing...");
+                    self.state = ClientState::Started(None);
+                    self.history.clear();
+                }
                 (ClientState::Started(_), MidiRealtimeMessage::MidiStop(t)) => {
                     info!(time = ?DoubleTime(time, t), "Stopping...");
                     self.state = ClientState::Stopped;
@@ -136,6 +151,10 @@ impl MidiSyncClient {
                 .as_ref()
                 .and_then(|lr| Some(now().0 - lr.0 .0 < Duration::from_secs_f64(1.0)))
                 .unwrap_or(false),
+            // Senders keep less than 300 ms between messages once sensing
+            has_sensing: self
+                .last_sensing
+                .is_some_and(|t| now().0 - t.0 < Duration::from_secs_f64(0.3)),
             total_quarters: (self.total_ticks + 1.) / self.tpqn, // TODO: Incorporate current time here
         }
     }
@@ -360,6 +379,9 @@ impl Widget for SyncClientWidget {
         if self.selected {
             outer = outer.red();
         }
+        if display.has_sensing {
+            outer = outer.title_top(Line::from(" SENSING ").right_aligned().cyan());
+        }
         match (&display.state, &display.has_clock) {
             (ClientState::Stopped, _) => {
                 outer = outer.title_top(Line::from(" STOPPED ").left_aligned().red().bold());
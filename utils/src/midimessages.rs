@@ -1,35 +1,526 @@
 use std::time::Duration;
 
+pub const MIDI_CLOCK: [u8; 1] = [248];
+pub const MIDI_TICK: [u8; 1] = [249];
 pub const MIDI_START: [u8; 1] = [250];
+pub const MIDI_CONTINUE: [u8; 1] = [251];
 pub const MIDI_STOP: [u8; 1] = [252];
-pub const MIDI_CLOCK: [u8; 1] = [248];
+pub const MIDI_ACTIVE_SENSING: [u8; 1] = [254];
+pub const MIDI_RESET: [u8; 1] = [255];
+
+/// Song Position Pointer, position in MIDI beats (sixteenth notes) since the start of the song
+pub fn song_position_pointer(sixteenths: u16) -> [u8; 3] {
+    let sixteenths = sixteenths.min(0x3FFF);
+    [0xF2, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8]
+}
 
-#[derive(Debug, Clone)]
+/// System Real-Time message with the time it was received
+#[derive(Debug, Clone, PartialEq)]
 pub enum MidiRealtimeMessage {
+    MidiClock(Duration),
+    /// 10 ms tick, undefined in the current MIDI spec but sent by some older gear
+    MidiTick(Duration),
     MidiStart(Duration),
+    MidiContinue(Duration),
     MidiStop(Duration),
-    MidiClock(Duration),
+    ActiveSensing(Duration),
+    Reset(Duration),
 }
 
 impl MidiRealtimeMessage {
+    /// A complete message, None for anything but a single realtime byte
     pub fn from_midi(micros: u64, data: &[u8]) -> Option<MidiRealtimeMessage> {
+        match data {
+            [status] => MidiRealtimeMessage::from_byte(micros, *status),
+            _ => None,
+        }
+    }
+
+    pub fn from_byte(micros: u64, status: u8) -> Option<MidiRealtimeMessage> {
         let t = Duration::from_micros(micros);
-        if data == MIDI_START.as_ref() {
-            Some(MidiRealtimeMessage::MidiStart(t))
-        } else if data == MIDI_STOP.as_ref() {
-            Some(MidiRealtimeMessage::MidiStop(t))
-        } else if data == MIDI_CLOCK.as_ref() {
-            Some(MidiRealtimeMessage::MidiClock(t))
-        } else {
-            None
+        match status {
+            0xF8 => Some(MidiRealtimeMessage::MidiClock(t)),
+            0xF9 => Some(MidiRealtimeMessage::MidiTick(t)),
+            0xFA => Some(MidiRealtimeMessage::MidiStart(t)),
+            0xFB => Some(MidiRealtimeMessage::MidiContinue(t)),
+            0xFC => Some(MidiRealtimeMessage::MidiStop(t)),
+            0xFE => Some(MidiRealtimeMessage::ActiveSensing(t)),
+            0xFF => Some(MidiRealtimeMessage::Reset(t)),
+            _ => None,
         }
     }
 
     pub fn to_midi(&self) -> &[u8] {
         match self {
             MidiRealtimeMessage::MidiClock(_) => MIDI_CLOCK.as_ref(),
+            MidiRealtimeMessage::MidiTick(_) => MIDI_TICK.as_ref(),
             MidiRealtimeMessage::MidiStart(_) => MIDI_START.as_ref(),
+            MidiRealtimeMessage::MidiContinue(_) => MIDI_CONTINUE.as_ref(),
             MidiRealtimeMessage::MidiStop(_) => MIDI_STOP.as_ref(),
+            MidiRealtimeMessage::ActiveSensing(_) => MIDI_ACTIVE_SENSING.as_ref(),
+            MidiRealtimeMessage::Reset(_) => MIDI_RESET.as_ref(),
+        }
+    }
+
+    pub fn time(&self) -> Duration {
+        match self {
+            MidiRealtimeMessage::MidiClock(t)
+            | MidiRealtimeMessage::MidiTick(t)
+            | MidiRealtimeMessage::MidiStart(t)
+            | MidiRealtimeMessage::MidiContinue(t)
+            | MidiRealtimeMessage::MidiStop(t)
+            | MidiRealtimeMessage::ActiveSensing(t)
+            | MidiRealtimeMessage::Reset(t) => *t,
+        }
+    }
+}
+
+/// System Common message, the data bytes are 7 bit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SystemCommonMessage {
+    /// Position in sixteenths, see song_position_pointer
+    SongPosition(u16),
+    /// MTC quarter frame, piece 0-7 and its nibble of the time code
+    QuarterFrame {
+        piece: u8,
+        value: u8,
+    },
+    SongSelect(u8),
+    TuneRequest,
+}
+
+impl SystemCommonMessage {
+    /// A complete message, None if the status is not System Common or data is missing
+    pub fn from_midi(data: &[u8]) -> Option<SystemCommonMessage> {
+        let (&status, data) = data.split_first()?;
+        if data.len() != common_data_len(status)? || data.iter().any(|b| b & 0x80 != 0) {
+            return None;
+        }
+        Some(match status {
+            0xF1 => SystemCommonMessage::QuarterFrame {
+                piece: data[0] >> 4,
+                value: data[0] & 0x0F,
+            },
+            0xF2 => SystemCommonMessage::SongPosition(data[0] as u16 | (data[1] as u16) << 7),
+            0xF3 => SystemCommonMessage::SongSelect(data[0]),
+            _ => SystemCommonMessage::TuneRequest,
+        })
+    }
+
+    pub fn to_midi(&self) -> Vec<u8> {
+        match *self {
+            SystemCommonMessage::SongPosition(sixteenths) => {
+                song_position_pointer(sixteenths).to_vec()
+            }
+            SystemCommonMessage::QuarterFrame { piece, value } => {
+                vec![0xF1, (piece & 0x07) << 4 | value & 0x0F]
+            }
+            SystemCommonMessage::SongSelect(song) => vec![0xF3, song & 0x7F],
+            SystemCommonMessage::TuneRequest => vec![0xF6],
+        }
+    }
+}
+
+// Data bytes following a System Common status, None for undefined ones
+fn common_data_len(status: u8) -> Option<usize> {
+    match status {
+        0xF1 | 0xF3 => Some(1),
+        0xF2 => Some(2),
+        0xF6 => Some(0),
+        _ => None,
+    }
+}
+
+// Data bytes following a channel voice status
+fn channel_data_len(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+/// Message taken from a byte stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum MidiMessage {
+    Realtime(MidiRealtimeMessage),
+    Common(SystemCommonMessage),
+    /// Channel voice or mode message, always with its status byte
+    Channel(Vec<u8>),
+    /// System exclusive message including F0 and F7
+    SysEx(Vec<u8>),
+}
+
+/* Splits a raw MIDI byte stream into messages. Realtime bytes may show up
+ * anywhere, even in the middle of another message, and are returned right
+ * away without disturbing it. Channel messages may use running status, which
+ * System Common and System Exclusive messages cancel. Data bytes that belong
+ * to no message are dropped. */
+#[derive(Debug, Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+    buf: Vec<u8>, // Message in progress, starting with its status
+    sysex: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser::default()
+    }
+
+    /// Feed one byte received at micros, returns the message it completes
+    pub fn push(&mut self, micros: u64, byte: u8) -> Option<MidiMessage> {
+        if byte >= 0xF8 {
+            // Undefined realtime bytes (FD) are ignored as well
+            return MidiRealtimeMessage::from_byte(micros, byte).map(MidiMessage::Realtime);
+        }
+        if byte == 0xF7 && self.sysex {
+            self.sysex = false;
+            self.buf.push(byte);
+            return Some(MidiMessage::SysEx(std::mem::take(&mut self.buf)));
+        }
+        if byte & 0x80 != 0 {
+            // Any other status ends an unfinished message, sysex or not
+            self.sysex = byte == 0xF0;
+            self.buf.clear();
+            self.buf.push(byte);
+            self.running_status = match byte {
+                0x80..=0xEF => Some(byte),
+                _ => None,
+            };
+        } else if self.sysex {
+            self.buf.push(byte);
+            return None;
+        } else {
+            if self.buf.is_empty() {
+                self.buf.extend(self.running_status);
+            }
+            if self.buf.is_empty() {
+                return None;
+            }
+            self.buf.push(byte);
+        }
+        self.complete()
+    }
+
+    /// All messages in data, e.g. a buffer read from a port
+    pub fn parse(&mut self, micros: u64, data: &[u8]) -> Vec<MidiMessage> {
+        data.iter().filter_map(|b| self.push(micros, *b)).collect()
+    }
+
+    fn complete(&mut self) -> Option<MidiMessage> {
+        let status = *self.buf.first()?;
+        match status {
+            0xF0 => None,
+            0x80..=0xEF if self.buf.len() > channel_data_len(status) => {
+                Some(MidiMessage::Channel(std::mem::take(&mut self.buf)))
+            }
+            0x80..=0xEF => None,
+            _ => match common_data_len(status) {
+                Some(len) if self.buf.len() > len => {
+                    let msg = SystemCommonMessage::from_midi(&self.buf);
+                    self.buf.clear();
+                    msg.map(MidiMessage::Common)
+                }
+                Some(_) => None,
+                // Undefined System Common (F4, F5) or a stray F7
+                None => {
+                    self.buf.clear();
+                    None
+                }
+            },
+        }
+    }
+}
+
+/// MIDI Time Code frame rates, numbered as in the MTC messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MtcRate {
+    Fps24,
+    Fps25,
+    /// 29.97 frames per second with drop frame numbering
+    Fps30Drop,
+    Fps30,
+}
+
+/// Time code position, counted in frames of rate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    pub rate: MtcRate,
+}
+
+impl MtcRate {
+    pub fn code(&self) -> u8 {
+        match self {
+            MtcRate::Fps24 => 0,
+            MtcRate::Fps25 => 1,
+            MtcRate::Fps30Drop => 2,
+            MtcRate::Fps30 => 3,
+        }
+    }
+
+    /// Frames per second in real time
+    pub fn fps(&self) -> f64 {
+        match self {
+            MtcRate::Fps24 => 24.0,
+            MtcRate::Fps25 => 25.0,
+            MtcRate::Fps30Drop => 30000.0 / 1001.0,
+            MtcRate::Fps30 => 30.0,
+        }
+    }
+
+    /// Frame numbers per second
+    pub fn nominal(&self) -> u64 {
+        match self {
+            MtcRate::Fps24 => 24,
+            MtcRate::Fps25 => 25,
+            MtcRate::Fps30Drop | MtcRate::Fps30 => 30,
+        }
+    }
+}
+
+impl std::fmt::Display for MtcRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MtcRate::Fps30Drop => f.write_str("29.97df"),
+            rate => write!(f, "{}", rate.nominal()),
+        }
+    }
+}
+
+impl Timecode {
+    /* Drop frame time code skips frame numbers 0 and 1 at the start of every
+     * minute, except every tenth minute, so the numbers keep up with real time.
+     * Positions wrap after 24 hours. */
+    pub fn from_frames(frames: u64, rate: MtcRate) -> Timecode {
+        let mut number = frames;
+        if rate == MtcRate::Fps30Drop {
+            let (tens, rest) = (frames / 17982, frames % 17982);
+            number += 18 * tens + 2 * (rest.saturating_sub(2) / 1798);
+        }
+        let fps = rate.nominal();
+        let number = number % (24 * 3600 * fps);
+        let seconds = number / fps;
+        Timecode {
+            hours: (seconds / 3600) as u8,
+            minutes: (seconds / 60 % 60) as u8,
+            seconds: (seconds % 60) as u8,
+            frames: (number % fps) as u8,
+            rate,
+        }
+    }
+
+    /// Position of the frame that is showing at time
+    pub fn from_duration(time: Duration, rate: MtcRate) -> Timecode {
+        Timecode::from_frames((time.as_secs_f64() * rate.fps()) as u64, rate)
+    }
+
+    /// One of the eight quarter frame messages, that together carry the position
+    pub fn quarter_frame(&self, piece: u8) -> [u8; 2] {
+        let piece = piece & 0x07;
+        let value = match piece {
+            0 => self.frames & 0x0F,
+            1 => self.frames >> 4 & 0x01,
+            2 => self.seconds & 0x0F,
+            3 => self.seconds >> 4 & 0x03,
+            4 => self.minutes & 0x0F,
+            5 => self.minutes >> 4 & 0x03,
+            6 => self.hours & 0x0F,
+            _ => self.rate.code() << 1 | self.hours >> 4 & 0x01,
+        };
+        [0xF1, piece << 4 | value]
+    }
+
+    /// Full frame SysEx message, sent to locate without running
+    pub fn full_frame(&self) -> [u8; 10] {
+        [
+            0xF0,
+            0x7F,
+            0x7F,
+            0x01,
+            0x01,
+            self.rate.code() << 5 | self.hours,
+            self.minutes,
+            self.seconds,
+            self.frames,
+            0xF7,
+        ]
+    }
+}
+
+impl std::fmt::Display for Timecode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = match self.rate {
+            MtcRate::Fps30Drop => ';',
+            _ => ':',
+        };
+        write!(
+            f,
+            "{:02}:{:02}:{:02}{}{:02}",
+            self.hours, self.minutes, self.seconds, separator, self.frames
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_realtime() {
+        for status in 0xF8..=0xFF {
+            match MidiRealtimeMessage::from_byte(1500, status) {
+                Some(msg) => {
+                    assert_eq!(msg.to_midi(), [status]);
+                    assert_eq!(msg.time(), Duration::from_micros(1500));
+                    assert_eq!(MidiRealtimeMessage::from_midi(1500, &[status]), Some(msg));
+                }
+                None => assert_eq!(status, 0xFD),
+            }
         }
+        assert_eq!(
+            MidiRealtimeMessage::from_midi(0, &MIDI_ACTIVE_SENSING),
+            Some(MidiRealtimeMessage::ActiveSensing(Duration::ZERO))
+        );
+        for status in 0x00..0xF8 {
+            assert!(MidiRealtimeMessage::from_byte(0, status).is_none());
+        }
+        assert!(MidiRealtimeMessage::from_midi(0, &[]).is_none());
+        assert!(MidiRealtimeMessage::from_midi(0, &[0xF8, 0xF8]).is_none());
+    }
+
+    #[test]
+    fn test_system_common() {
+        let messages = [
+            (SystemCommonMessage::SongPosition(0), vec![0xF2, 0x00, 0x00]),
+            (
+                SystemCommonMessage::SongPosition(300),
+                vec![0xF2, 0x2C, 0x02],
+            ),
+            (
+                SystemCommonMessage::SongPosition(0x3FFF),
+                vec![0xF2, 0x7F, 0x7F],
+            ),
+            (
+                SystemCommonMessage::QuarterFrame { piece: 7, value: 6 },
+                vec![0xF1, 0x76],
+            ),
+            (SystemCommonMessage::SongSelect(127), vec![0xF3, 0x7F]),
+            (SystemCommonMessage::TuneRequest, vec![0xF6]),
+        ];
+        for (msg, bytes) in messages {
+            assert_eq!(msg.to_midi(), bytes);
+            assert_eq!(SystemCommonMessage::from_midi(&bytes), Some(msg));
+        }
+        // Matches what the time code sends
+        let tc = Timecode::from_frames(1000, MtcRate::Fps25);
+        for piece in 0..8 {
+            let qf = SystemCommonMessage::from_midi(&tc.quarter_frame(piece)).unwrap();
+            assert!(matches!(qf, SystemCommonMessage::QuarterFrame { piece: p, .. } if p == piece));
+            assert_eq!(qf.to_midi(), tc.quarter_frame(piece));
+        }
+        // Missing, extra or invalid data bytes and other status bytes
+        for bytes in [
+            &[][..],
+            &[0xF2, 0x10],
+            &[0xF2, 0x10, 0x00, 0x00],
+            &[0xF3, 0x80],
+            &[0xF6, 0x00],
+            &[0xF4],
+            &[0xF5, 0x01],
+            &[0xF0, 0x7E, 0xF7],
+            &[0xF8],
+            &[0x90, 0x3C, 0x40],
+        ] {
+            assert!(
+                SystemCommonMessage::from_midi(bytes).is_none(),
+                "{:02X?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn test_parser() {
+        let clock = |micros| MidiMessage::Realtime(MidiRealtimeMessage::MidiClock(micros));
+        let t = Duration::from_micros(42);
+        let mut parser = MidiParser::new();
+        let stream = [
+            0x3C, // Data without status
+            0x90, 0x3C, 0xF8, 0x64, // Clock inside a note on
+            0x3E, 0xF8, 0xFD, 0x00, // Running status, undefined realtime byte
+            0xC0, 0x05, 0x06, // Program changes with running status
+            0xF2, 0x10, 0xF8, 0x01, 0x40, // Song position cancels running status
+            0xF0, 0x7E, 0xFE, 0x01, 0xF7, // Active sensing inside sysex
+            0xB0, 0x07, 0xF1, 0x23, // Quarter frame cuts off the control change
+            0xF5, 0x01, 0xF6, // Undefined common status, tune request
+            0xFA, 0xFB, 0xFC, 0xFF, 0xF9, 0xF7, // Stray end of sysex
+            0x80, 0x3C, 0x00,
+        ];
+        let messages = parser.parse(42, &stream);
+        assert_eq!(
+            messages,
+            vec![
+                clock(t),
+                MidiMessage::Channel(vec![0x90, 0x3C, 0x64]),
+                clock(t),
+                MidiMessage::Channel(vec![0x90, 0x3E, 0x00]),
+                MidiMessage::Channel(vec![0xC0, 0x05]),
+                MidiMessage::Channel(vec![0xC0, 0x06]),
+                clock(t),
+                MidiMessage::Common(SystemCommonMessage::SongPosition(0x90)),
+                MidiMessage::Realtime(MidiRealtimeMessage::ActiveSensing(t)),
+                MidiMessage::SysEx(vec![0xF0, 0x7E, 0x01, 0xF7]),
+                MidiMessage::Common(SystemCommonMessage::QuarterFrame { piece: 2, value: 3 }),
+                MidiMessage::Common(SystemCommonMessage::TuneRequest),
+                MidiMessage::Realtime(MidiRealtimeMessage::MidiStart(t)),
+                MidiMessage::Realtime(MidiRealtimeMessage::MidiContinue(t)),
+                MidiMessage::Realtime(MidiRealtimeMessage::MidiStop(t)),
+                MidiMessage::Realtime(MidiRealtimeMessage::Reset(t)),
+                MidiMessage::Realtime(MidiRealtimeMessage::MidiTick(t)),
+                MidiMessage::Channel(vec![0x80, 0x3C, 0x00]),
+            ]
+        );
+
+        // Messages split over several buffers, and the serialized form parses again
+        let mut parser = MidiParser::new();
+        assert!(parser.parse(0, &[0xF2, 0x7F]).is_empty());
+        assert_eq!(
+            parser.parse(0, &[0x7F, 0xF3]),
+            vec![MidiMessage::Common(SystemCommonMessage::SongPosition(
+                0x3FFF
+            ))]
+        );
+        assert_eq!(
+            parser.parse(0, &[0x02]),
+            vec![MidiMessage::Common(SystemCommonMessage::SongSelect(2))]
+        );
+        // No running status after System Common
+        assert!(parser.parse(0, &[0x03]).is_empty());
+    }
+
+    #[test]
+    fn test_timecode() {
+        let tc = Timecode::from_duration(Duration::from_millis(3_723_500), MtcRate::Fps25);
+        assert_eq!(tc.to_string(), "01:02:03:12");
+        assert_eq!(
+            tc.full_frame(),
+            [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 2, 3, 12, 0xF7]
+        );
+        let pieces: Vec<u8> = (0..8).map(|p| tc.quarter_frame(p)[1]).collect();
+        assert_eq!(pieces, [0x0C, 0x10, 0x23, 0x30, 0x42, 0x50, 0x61, 0x72]);
+
+        // Drop frame skips 00 and 01 at every minute but the tenth
+        let df = |frames| Timecode::from_frames(frames, MtcRate::Fps30Drop).to_string();
+        assert_eq!(df(1799), "00:00:59;29");
+        assert_eq!(df(1800), "00:01:00;02");
+        assert_eq!(df(17982), "00:10:00;00");
+        assert_eq!(df(17982 * 6), "01:00:00;00");
+        let tc = Timecode::from_frames(24 * 3600 * 30 + 1, MtcRate::Fps30);
+        assert_eq!(tc.to_string(), "00:00:00:01");
+        assert_eq!(tc.quarter_frame(7), [0xF1, 0x76]);
     }
 }